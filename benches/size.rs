@@ -0,0 +1,56 @@
+#![feature(test)]
+
+extern crate mincode;
+extern crate rustc_serialize;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate test;
+
+use test::{black_box, Bencher};
+
+use mincode::FloatEncoding;
+use mincode::SizeLimit::{Bounded, Infinite};
+
+#[derive(RustcEncodable, Serialize)]
+struct Entity {
+    id: u64,
+    x: i32,
+    y: i32,
+    hp: u16,
+}
+
+fn world() -> Vec<Entity> {
+    (0..1000u64).map(|i| Entity {
+        id: i * 7919,
+        x: -(i as i32) * 300,
+        y: i as i32 * 70000,
+        hp: (i % 1000) as u16,
+    }).collect()
+}
+
+// Computing the size should be much cheaper than actually serializing,
+// it does not allocate at all (see `sizes_do_not_allocate` in tests/alloc.rs).
+
+#[bench]
+fn serialized_size(b: &mut Bencher) {
+    let world = world();
+    b.iter(|| mincode::serde::serialized_size(black_box(&world), FloatEncoding::Normal));
+}
+
+#[bench]
+fn encoded_size(b: &mut Bencher) {
+    let world = world();
+    b.iter(|| mincode::rustc_serialize::encoded_size(black_box(&world), FloatEncoding::Normal));
+}
+
+#[bench]
+fn serialize_infinite(b: &mut Bencher) {
+    let world = world();
+    b.iter(|| mincode::serde::serialize(black_box(&world), Infinite, FloatEncoding::Normal).unwrap());
+}
+
+#[bench]
+fn serialize_bounded(b: &mut Bencher) {
+    let world = world();
+    b.iter(|| mincode::serde::serialize(black_box(&world), Bounded(1 << 20), FloatEncoding::Normal).unwrap());
+}
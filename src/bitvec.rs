@@ -1,11 +1,19 @@
+use std::cmp;
+
 #[cfg(feature = "rustc-serialize")]
 use rustc_serialize_crate::{Encodable, Encoder, Decodable, Decoder};
 
 #[cfg(feature = "serde")]
 use serde_crate as serde;
+#[cfg(feature = "serde")]
+use std::fmt;
 
 pub use bit_vec::BitVec;
 
+/// The most bytes that are allocated up front for the bits, as the bit length
+/// comes from the input and the bytes may never arrive.
+const MAX_PREALLOCATED: usize = 4096;
+
 #[derive(Debug, PartialEq, PartialOrd, Eq, Ord, Hash, Clone)]
 pub struct BVec {
     vec: BitVec,
@@ -41,8 +49,8 @@ impl Decodable for BVec {
     fn decode<D: Decoder>(d: &mut D) -> Result<BVec, D::Error> {
         let bit_len: usize = Decodable::decode(d)?;
         //let bytes: Vec<u8> = Decodable::decode(d)?;
-        let byte_len = if bit_len % 8 == 0 { bit_len / 8 } else { bit_len / 8 + 1 };
-        let mut bytes = Vec::with_capacity(byte_len);
+        let byte_len = bit_len.div_ceil(8);
+        let mut bytes = Vec::with_capacity(cmp::min(byte_len, MAX_PREALLOCATED));
         for _ in 0..byte_len {
             bytes.push(Decodable::decode(d)?);
         }
@@ -54,32 +62,54 @@ impl Decodable for BVec {
 
 #[cfg(feature = "serde")]
 impl serde::Serialize for BVec {
-    fn serialize<S>(&self, s: &mut S) -> Result<(), S::Error>
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
         where S: serde::Serializer
     {
-        serde::Serialize::serialize(&self.vec.len(), s)?;
+        use serde_crate::ser::SerializeTuple;
 
         // avoid encoding the Vec's len
-        for b in self.vec.to_bytes() {
-            serde::Serialize::serialize(&b, s)?;
+        let bytes = self.vec.to_bytes();
+        let mut tuple = s.serialize_tuple(1 + bytes.len())?;
+        tuple.serialize_element(&self.vec.len())?;
+        for b in bytes {
+            tuple.serialize_element(&b)?;
         }
-        Ok(())
+        tuple.end()
     }
 }
 
 #[cfg(feature = "serde")]
-impl serde::Deserialize for BVec {
-    fn deserialize<D>(d: &mut D) -> Result<Self, D::Error>
-        where D: serde::Deserializer
+impl<'de> serde::Deserialize<'de> for BVec {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
     {
-        let bit_len: usize = serde::Deserialize::deserialize(d)?;
-        let byte_len = if bit_len % 8 == 0 { bit_len / 8 } else { bit_len / 8 + 1 };
-        let mut bytes = Vec::with_capacity(byte_len);
-        for _ in 0..byte_len {
-            bytes.push(serde::Deserialize::deserialize(d)?);
+        struct BVecVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BVecVisitor {
+            type Value = BVec;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a bit length followed by the bytes of the bits")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<BVec, A::Error>
+                where A: serde::de::SeqAccess<'de>
+            {
+                let bit_len: usize = seq.next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                let byte_len = bit_len.div_ceil(8);
+                let mut bytes = Vec::with_capacity(cmp::min(byte_len, MAX_PREALLOCATED));
+                for i in 0..byte_len {
+                    bytes.push(seq.next_element()?
+                        .ok_or_else(|| serde::de::Error::invalid_length(i + 1, &self))?);
+                }
+                let mut vec = BitVec::from_bytes(&bytes);
+                unsafe { vec.set_len(bit_len); }
+                Ok(BVec { vec: vec })
+            }
         }
-        let mut vec = BitVec::from_bytes(&bytes);
-        unsafe { vec.set_len(bit_len); }
-        Ok(BVec { vec: vec })
+
+        // The number of bytes is only known once the bit length has been read.
+        d.deserialize_tuple(usize::MAX, BVecVisitor)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,133 @@
+
+use float::FloatEncoding;
+
+/// How structs will be encoded.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum StructEncoding {
+    /// Fields are encoded one after another, exactly like a tuple.
+    Tuple,
+    /// The fields are prefixed with their total encoded length in bytes.
+    ///
+    /// This makes it possible to append fields to a struct without breaking
+    /// older readers or writers: a reader that knows fewer fields skips the
+    /// unknown trailing bytes, and a reader that knows more fields fills the
+    /// missing trailing ones in from `#[serde(default)]`.
+    ///
+    /// Only the serde backend supports this, struct variants of enums are
+    /// not affected.
+    LengthPrefixed,
+    /// The fields are encoded like a tuple, but the `Option` fields have no
+    /// tag byte. Instead a bitmap in front of the fields has a bit for each of
+    /// them, set if it is `Some`, and a `None` takes no bytes at all.
+    ///
+    /// The bitmap takes a byte for every seven `Option` fields up to the last
+    /// one that is `Some`, so this pays off for structs with many `Option`
+    /// fields that are mostly `None`.
+    ///
+    /// Only the serde backend supports this, struct variants of enums are
+    /// not affected.
+    PresenceBitmap,
+}
+
+/// The options that control how values are encoded and decoded.
+///
+/// Encoder and decoder must use the same `Config`.
+/// A `FloatEncoding` converts into a `Config` with default values for
+/// all other options.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Config {
+    pub float_enc: FloatEncoding,
+    pub struct_enc: StructEncoding,
+    /// Allows only one encoding per value, e.g. for values that are signed.
+    ///
+    /// NaNs are encoded as the canonical NaN, and decoding fails with
+    /// `NonCanonical` on overlong integers and lengths, other NaNs, and
+    /// length prefixed structs with missing or unknown fields, and maps whose
+    /// keys are not in strictly ascending order. Maps are encoded as with
+    /// `sort_maps`. Only the serde backend supports this.
+    pub canonical: bool,
+    /// Encodes the entries of maps sorted by the encoded bytes of their keys,
+    /// so that e.g. a `HashMap` encodes the same regardless of its iteration
+    /// order. Only the serde backend supports this.
+    pub sort_maps: bool,
+    /// Encodes tuples, and so fixed-size arrays, with their length in front.
+    ///
+    /// serde serializes arrays exactly like tuples, so they are encoded
+    /// without a length by default. Older versions wrote the length of
+    /// arrays, and of no other tuples, so their data reads with this as long
+    /// as it contains no tuples other than arrays. Arrays next to such tuples
+    /// can be read with `serde::len_prefixed` instead. Only the serde backend
+    /// supports this, `rustc_serialize` always writes the length of arrays.
+    pub array_len_prefix: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            float_enc: FloatEncoding::Normal,
+            struct_enc: StructEncoding::Tuple,
+            canonical: false,
+            sort_maps: false,
+            array_len_prefix: false,
+        }
+    }
+}
+
+impl From<FloatEncoding> for Config {
+    fn from(float_enc: FloatEncoding) -> Config {
+        Config {
+            float_enc: float_enc,
+            ..Config::default()
+        }
+    }
+}
+
+/// Limits on what decoding untrusted input may allocate.
+///
+/// A `SizeLimit` bounds the bytes that are read, but a length prefix of a few
+/// bytes can still claim a collection of billions of elements. Lengths over
+/// these limits fail with a `SizeLimit` error before anything is allocated for
+/// them. Values nested deeper than `max_depth` fail with a `DepthLimit` error,
+/// so that hostile input cannot recurse until the stack overflows. The
+/// default has no limits but the depth, which is 512: an `Option<Box<Node>>`
+/// in a `Node` struct takes two levels per node, and 512 levels still fit
+/// into the 2 MiB stack of a spawned thread in a debug build.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct DecodeLimits {
+    /// The most bytes of all strings and byte buffers together, where every
+    /// element of a sequence and entry of a map counts as one byte, as their
+    /// size is not known while decoding.
+    pub max_alloc: u64,
+    /// The most elements of a sequence or entries of a map.
+    pub max_seq_len: u64,
+    /// The most bytes of a single string or byte buffer.
+    pub max_str_len: u64,
+    /// The most sequences, maps, structs, tuples, enums and options that may
+    /// be nested in each other.
+    pub max_depth: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> DecodeLimits {
+        DecodeLimits {
+            max_alloc: u64::MAX,
+            max_seq_len: u64::MAX,
+            max_str_len: u64::MAX,
+            max_depth: 512,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Counts a length read from the input against the limits, `max` is the
+    /// limit for a single one of its kind. Returns the new total.
+    pub(crate) fn charge(&self, allocated: u64, len: u64, max: u64) -> Option<u64> {
+        if len > max {
+            return None;
+        }
+        match allocated.checked_add(len) {
+            Some(allocated) if allocated <= self.max_alloc => Some(allocated),
+            _ => None,
+        }
+    }
+}
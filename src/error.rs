@@ -0,0 +1,209 @@
+use std::fmt;
+
+use io::IoError;
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+#[cfg(feature = "std")]
+pub use std::error::Error as StdError;
+// serde has a stand-in for `std::error::Error` without `std`.
+#[cfg(all(not(feature = "std"), feature = "serde"))]
+pub use serde_crate::ser::StdError;
+
+/// An error from either the `serde` or the `rustc_serialize` backend.
+///
+/// The backend specific error types convert into this one with `From`, so that
+/// code using both backends can share its error handling.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    position: Option<ErrorPosition>,
+}
+
+/// A specialized `Result` type for mincode operations.
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// The different kinds of errors that can occur while encoding or decoding.
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// An error originating from the underlying `Reader` or `Writer`.
+    Io(IoError),
+    /// The bytes are not decodable because of an invalid encoding.
+    InvalidEncoding {
+        desc: &'static str,
+        detail: Option<String>,
+    },
+    /// The bytes decode to a value, but are not its canonical encoding.
+    NonCanonical {
+        desc: &'static str,
+        detail: Option<String>,
+    },
+    /// Encoding or decoding would take more bytes than the size limit allows.
+    SizeLimit,
+    /// Decoding a value nested deeper than `DecodeLimits::max_depth`.
+    DepthLimit,
+    /// An object did not fit into the buffer it was encoded into, it needs
+    /// `required` bytes.
+    BufferTooSmall { required: u64 },
+    /// An error reported by the value being encoded or decoded, e.g. an unknown
+    /// enum variant or a missing field.
+    Custom(String),
+}
+
+/// Where an error occurred while encoding or decoding a value.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ErrorPosition {
+    /// The number of bytes read or written when the error occurred.
+    pub offset: u64,
+    root: Option<String>,
+    fields: String,
+}
+
+/// A step on the path to the value that caused an error.
+pub enum Segment<'a> {
+    /// Entering a struct, only the outermost one is part of the path.
+    Struct(&'a str),
+    /// A named field of a struct.
+    Field(&'a str),
+    /// An element of a tuple, tuple struct or tuple variant.
+    Element(usize),
+    /// An element of a sequence or an entry of a map.
+    Index(usize),
+}
+
+impl ErrorPosition {
+    pub(crate) fn new(offset: u64) -> ErrorPosition {
+        ErrorPosition {
+            offset: offset,
+            root: None,
+            fields: String::new(),
+        }
+    }
+
+    /// Returns the path to the value that caused the error, e.g. `World.entities[3].x`.
+    ///
+    /// The path is empty if the error occurred outside of any struct, tuple or collection.
+    pub fn path(&self) -> String {
+        match self.root {
+            Some(ref root) => format!("{}{}", root, self.fields),
+            None => self.fields.clone(),
+        }
+    }
+
+    /// Prepends a segment to the path, the path is built from the inside out
+    /// while the error propagates.
+    pub(crate) fn push_front(&mut self, segment: Segment) {
+        let prefix = match segment {
+            Segment::Struct(name) => {
+                self.root = Some(name.to_string());
+                return;
+            }
+            Segment::Field(name) => format!(".{}", name),
+            Segment::Element(index) => format!(".{}", index),
+            Segment::Index(index) => format!("[{}]", index),
+        };
+        // The struct we are in is not the outermost one.
+        self.root = None;
+        self.fields.insert_str(0, &prefix);
+    }
+}
+
+impl fmt::Display for ErrorPosition {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(fmt, "at byte {}", self.offset));
+        let path = self.path();
+        if !path.is_empty() {
+            try!(write!(fmt, " in `{}`", path));
+        }
+        Ok(())
+    }
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, position: Option<ErrorPosition>) -> Error {
+        Error {
+            kind: kind,
+            position: position,
+        }
+    }
+
+    /// Returns the kind of this error.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Returns the kind of this error, consuming it.
+    pub fn into_kind(self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns where in the input or output the error occurred, if known.
+    pub fn position(&self) -> Option<&ErrorPosition> {
+        self.position.as_ref()
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error::new(kind, None)
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(err: IoError) -> Error {
+        Error::new(ErrorKind::Io(err), None)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::Io(ref err) => write!(fmt, "IoError: {}", err),
+            ErrorKind::InvalidEncoding { desc, detail: None } =>
+                write!(fmt, "InvalidEncoding: {}", desc),
+            ErrorKind::InvalidEncoding { desc, detail: Some(ref detail) } =>
+                write!(fmt, "InvalidEncoding: {} ({})", desc, detail),
+            ErrorKind::NonCanonical { desc, detail: None } =>
+                write!(fmt, "NonCanonical: {}", desc),
+            ErrorKind::NonCanonical { desc, detail: Some(ref detail) } =>
+                write!(fmt, "NonCanonical: {} ({})", desc, detail),
+            ErrorKind::SizeLimit => write!(fmt, "SizeLimit"),
+            ErrorKind::DepthLimit => write!(fmt, "DepthLimit"),
+            ErrorKind::BufferTooSmall { required } =>
+                write!(fmt, "BufferTooSmall: {} bytes required", required),
+            ErrorKind::Custom(ref msg) => write!(fmt, "{}", msg),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some(ref position) => write!(fmt, "{} {}", self.kind, position),
+            None => self.kind.fmt(fmt),
+        }
+    }
+}
+
+#[cfg(any(feature = "std", feature = "serde"))]
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match self.kind {
+            ErrorKind::Io(ref err) => StdError::description(err),
+            ErrorKind::InvalidEncoding { desc, .. } => desc,
+            ErrorKind::NonCanonical { desc, .. } => desc,
+            ErrorKind::SizeLimit => "the size limit has been reached",
+            ErrorKind::DepthLimit => "the depth limit has been reached",
+            ErrorKind::BufferTooSmall { .. } => "the buffer is too small for the encoded object",
+            ErrorKind::Custom(ref msg) => msg,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self.kind {
+            ErrorKind::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -1,12 +1,9 @@
-use std::io::{Read, Write};
-use std::io::Error as IoError;
-
-use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
-
-use num_traits;
+use byteorder::{ByteOrder, LittleEndian};
 
 use half::f16;
 
+use io::{IoError, Reader, Writer};
+
 /// How floats will be encoded.
 #[repr(usize)]
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
@@ -21,8 +18,8 @@ pub enum FloatEncoding {
     HalvePrecision,
 }
 
-pub type FloatEncoder<F: num_traits::Float> = fn(&mut Write, F) -> Result<(), IoError>;
-pub type FloatDecoder<F: num_traits::Float> = fn(&mut Read) -> Result<F, IoError>;
+pub type FloatEncoder<F> = fn(&mut Writer, F) -> Result<(), IoError>;
+pub type FloatDecoder<F> = fn(&mut Reader) -> Result<F, IoError>;
 
 static FLOAT_ENCODERS: [(FloatEncoder<f32>, FloatEncoder<f64>); 4] = [
     (write_f32_normal, write_f64_normal),
@@ -62,34 +59,50 @@ pub fn float_sizes(float_enc: FloatEncoding) -> (usize, usize) {
     unsafe { *FLOAT_SIZES.get_unchecked(float_enc as usize) }
 }
 
-fn write_f32_normal(w: &mut Write, v: f32) -> Result<(), IoError> {
-    w.write_f32::<LittleEndian>(v)
+fn write_f32_normal(w: &mut Writer, v: f32) -> Result<(), IoError> {
+    let mut buf = [0; 4];
+    LittleEndian::write_f32(&mut buf, v);
+    w.write_all(&buf)
 }
-fn write_f64_normal(w: &mut Write, v: f64) -> Result<(), IoError> {
-    w.write_f64::<LittleEndian>(v)
+fn write_f64_normal(w: &mut Writer, v: f64) -> Result<(), IoError> {
+    let mut buf = [0; 8];
+    LittleEndian::write_f64(&mut buf, v);
+    w.write_all(&buf)
 }
-fn write_f32_f16(w: &mut Write, v: f32) -> Result<(), IoError> {
-    w.write_u16::<LittleEndian>(f16::from_f32(v).as_bits())
+fn write_f32_f16(w: &mut Writer, v: f32) -> Result<(), IoError> {
+    let mut buf = [0; 2];
+    LittleEndian::write_u16(&mut buf, f16::from_f32(v).as_bits());
+    w.write_all(&buf)
 }
-fn write_f64_f16(w: &mut Write, v: f64) -> Result<(), IoError> {
-    w.write_u16::<LittleEndian>(f16::from_f64(v).as_bits())
+fn write_f64_f16(w: &mut Writer, v: f64) -> Result<(), IoError> {
+    let mut buf = [0; 2];
+    LittleEndian::write_u16(&mut buf, f16::from_f64(v).as_bits());
+    w.write_all(&buf)
 }
-fn write_f64_f32(w: &mut Write, v: f64) -> Result<(), IoError> {
-    w.write_f32::<LittleEndian>(v as f32)
+fn write_f64_f32(w: &mut Writer, v: f64) -> Result<(), IoError> {
+    write_f32_normal(w, v as f32)
 }
 
-fn read_f32_normal(r: &mut Read) -> Result<f32, IoError> {
-    r.read_f32::<LittleEndian>()
+fn read_f32_normal(r: &mut Reader) -> Result<f32, IoError> {
+    let mut buf = [0; 4];
+    try!(r.read_exact(&mut buf));
+    Ok(LittleEndian::read_f32(&buf))
 }
-fn read_f64_normal(r: &mut Read) -> Result<f64, IoError> {
-    r.read_f64::<LittleEndian>()
+fn read_f64_normal(r: &mut Reader) -> Result<f64, IoError> {
+    let mut buf = [0; 8];
+    try!(r.read_exact(&mut buf));
+    Ok(LittleEndian::read_f64(&buf))
 }
-fn read_f32_f16(r: &mut Read) -> Result<f32, IoError> {
-    r.read_u16::<LittleEndian>().map(|v| f32::from(f16::from_bits(v)))
+fn read_f32_f16(r: &mut Reader) -> Result<f32, IoError> {
+    let mut buf = [0; 2];
+    try!(r.read_exact(&mut buf));
+    Ok(f32::from(f16::from_bits(LittleEndian::read_u16(&buf))))
 }
-fn read_f64_f16(r: &mut Read) -> Result<f64, IoError> {
-    r.read_u16::<LittleEndian>().map(|v| f64::from(f16::from_bits(v)))
+fn read_f64_f16(r: &mut Reader) -> Result<f64, IoError> {
+    let mut buf = [0; 2];
+    try!(r.read_exact(&mut buf));
+    Ok(f64::from(f16::from_bits(LittleEndian::read_u16(&buf))))
 }
-fn read_f64_f32(r: &mut Read) -> Result<f64, IoError> {
-    r.read_f32::<LittleEndian>().map(|v| v as f64)
+fn read_f64_f32(r: &mut Reader) -> Result<f64, IoError> {
+    read_f32_normal(r).map(|v| v as f64)
 }
@@ -0,0 +1,180 @@
+//! The byte sources and sinks that values are decoded from and encoded into.
+//!
+//! With the `std` feature every `std::io::Read` is a `Reader` and every
+//! `std::io::Write` is a `Writer`. Without it, byte slices are readers and
+//! `Vec<u8>` and mutable byte slices are writers.
+
+#[cfg(not(feature = "std"))]
+use std::fmt;
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+/// The error of a `Reader` or `Writer`.
+#[cfg(feature = "std")]
+pub type IoError = ::std::io::Error;
+
+/// The error of a `Reader` or `Writer`.
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IoError {
+    /// The reader ran out of bytes.
+    UnexpectedEof,
+    /// The writer ran out of space.
+    WriteZero,
+}
+
+#[cfg(not(feature = "std"))]
+impl fmt::Display for IoError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IoError::UnexpectedEof => write!(fmt, "failed to fill whole buffer"),
+            IoError::WriteZero => write!(fmt, "failed to write whole buffer"),
+        }
+    }
+}
+
+#[cfg(all(not(feature = "std"), feature = "serde"))]
+impl ::error::StdError for IoError {
+    fn description(&self) -> &str {
+        match *self {
+            IoError::UnexpectedEof => "failed to fill whole buffer",
+            IoError::WriteZero => "failed to write whole buffer",
+        }
+    }
+}
+
+/// A source of bytes to decode values from.
+pub trait Reader {
+    /// Reads exactly enough bytes to fill `buf`.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoError>;
+}
+
+/// A sink for the bytes of encoded values.
+pub trait Writer {
+    /// Writes all of `buf`.
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IoError>;
+}
+
+#[cfg(feature = "std")]
+impl<R: ::std::io::Read + ?Sized> Reader for R {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoError> {
+        ::std::io::Read::read_exact(self, buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: ::std::io::Write + ?Sized> Writer for W {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        ::std::io::Write::write_all(self, buf)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<R: Reader + ?Sized> Reader for &mut R {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoError> {
+        (**self).read_exact(buf)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Reader for &[u8] {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoError> {
+        if buf.len() > self.len() {
+            return Err(IoError::UnexpectedEof);
+        }
+        let (head, tail) = self.split_at(buf.len());
+        buf.copy_from_slice(head);
+        *self = tail;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<W: Writer + ?Sized> Writer for &mut W {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        (**self).write_all(buf)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Writer for &mut [u8] {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        if buf.len() > self.len() {
+            return Err(IoError::WriteZero);
+        }
+        let (head, tail) = ::std::mem::take(self).split_at_mut(buf.len());
+        head.copy_from_slice(buf);
+        *self = tail;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Writer for Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Reads a single byte.
+pub fn read_u8<R: Reader + ?Sized>(reader: &mut R) -> Result<u8, IoError> {
+    let mut buf = [0];
+    try!(reader.read_exact(&mut buf));
+    Ok(buf[0])
+}
+
+/// Reads a single byte, or returns `None` if the reader is at its end.
+pub fn read_u8_or_eof<R: Reader + ?Sized>(reader: &mut R) -> Result<Option<u8>, IoError> {
+    let mut buf = [0];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf[0])),
+        Err(ref err) if is_eof(err) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(feature = "std")]
+fn is_eof(err: &IoError) -> bool {
+    err.kind() == ::std::io::ErrorKind::UnexpectedEof
+}
+
+#[cfg(not(feature = "std"))]
+fn is_eof(err: &IoError) -> bool {
+    *err == IoError::UnexpectedEof
+}
+
+/// Reads `len` bytes into a `Vec`.
+pub fn read_to_vec<R: Reader + ?Sized>(reader: &mut R, len: u64) -> Result<Vec<u8>, IoError> {
+    let mut buffer = Vec::new();
+    try!(append_to_vec(reader, len, &mut buffer));
+    Ok(buffer)
+}
+
+/// Reads `len` bytes and appends them to `buffer`.
+///
+/// The `Vec` grows while reading, so that a bogus length can not make us
+/// allocate more memory than there is input.
+pub fn append_to_vec<R: Reader + ?Sized>(reader: &mut R, len: u64, buffer: &mut Vec<u8>) -> Result<(), IoError> {
+    let mut chunk = [0; 256];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = if remaining < chunk.len() as u64 { remaining as usize } else { chunk.len() };
+        try!(reader.read_exact(&mut chunk[..n]));
+        buffer.extend_from_slice(&chunk[..n]);
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// Reads and discards `count` bytes.
+pub fn skip<R: Reader + ?Sized>(reader: &mut R, count: u64) -> Result<(), IoError> {
+    let mut chunk = [0; 256];
+    let mut remaining = count;
+    while remaining > 0 {
+        let n = if remaining < chunk.len() as u64 { remaining as usize } else { chunk.len() };
+        try!(reader.read_exact(&mut chunk[..n]));
+        remaining -= n as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use serde_crate as serde;
+
+use ::Config;
+use serde::{BytesVisitor, DeserializeResult, SerializeResult};
+
+/// A value that is kept in its encoded form and only decoded on demand.
+///
+/// On the wire a `Lazy<T>` is the encoded `T`, prefixed with its length like
+/// a byte slice. Decoding a message that contains one only copies those bytes,
+/// so handlers that do not care about the value do not pay for decoding it,
+/// and forwarding the message encodes the bytes as they are.
+///
+/// The encoded bytes do not know the `Config` they were encoded with, so the
+/// same one has to be passed to `new` and `get`.
+///
+/// ```rust
+/// # extern crate mincode;
+/// # extern crate serde;
+/// use mincode::{Lazy, FloatEncoding, SizeLimit};
+/// use mincode::serde::{serialize, deserialize};
+///
+/// # fn main() {
+/// let payload = Lazy::new(&vec![1.5f32, 2.5], FloatEncoding::Normal).unwrap();
+/// let bytes = serialize(&(7u8, payload), SizeLimit::Infinite, FloatEncoding::Normal).unwrap();
+///
+/// let (tag, payload): (u8, Lazy<Vec<f32>>) = deserialize(&bytes, FloatEncoding::Normal).unwrap();
+/// assert_eq!(tag, 7);
+/// assert_eq!(payload.get(FloatEncoding::Normal).unwrap(), vec![1.5, 2.5]);
+/// # }
+/// ```
+pub struct Lazy<T> {
+    bytes: Vec<u8>,
+    marker: PhantomData<T>,
+}
+
+impl<T> Lazy<T> {
+    /// Wraps bytes that are already the encoding of a `T`.
+    pub fn from_bytes(bytes: Vec<u8>) -> Lazy<T> {
+        Lazy {
+            bytes: bytes,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the encoded value.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the encoded value.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl<T: serde::Serialize> Lazy<T> {
+    /// Encodes a value.
+    pub fn new<C: Into<Config>>(value: &T, config: C) -> SerializeResult<Lazy<T>> {
+        let bytes = try!(::serde::serialize(value, ::SizeLimit::Infinite, config));
+        Ok(Lazy::from_bytes(bytes))
+    }
+}
+
+impl<T: serde::de::DeserializeOwned> Lazy<T> {
+    /// Decodes the value.
+    pub fn get<C: Into<Config>>(&self, config: C) -> DeserializeResult<T> {
+        ::serde::deserialize(&self.bytes, config)
+    }
+}
+
+impl<T> Clone for Lazy<T> {
+    fn clone(&self) -> Lazy<T> {
+        Lazy::from_bytes(self.bytes.clone())
+    }
+}
+
+impl<T> PartialEq for Lazy<T> {
+    fn eq(&self, other: &Lazy<T>) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl<T> Eq for Lazy<T> {}
+
+impl<T> fmt::Debug for Lazy<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Lazy({} bytes)", self.bytes.len())
+    }
+}
+
+impl<T> serde::Serialize for Lazy<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        serializer.serialize_bytes(&self.bytes)
+    }
+}
+
+impl<'de, T> serde::Deserialize<'de> for Lazy<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Lazy<T>, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        let bytes = try!(deserializer.deserialize_byte_buf(BytesVisitor));
+        Ok(Lazy::from_bytes(bytes))
+    }
+}
@@ -3,8 +3,8 @@
 //!
 //! There are simple functions for encoding to `Vec<u8>` and decoding from
 //! `&[u8]`, but the meat of the library is the `encode_into` and `decode_from`
-//! functions which respectively allow encoding into a `std::io::Writer`
-//! and decoding from a `std::io::Buffer`.
+//! functions which respectively allow encoding into a `Writer`
+//! and decoding from a `Reader`.
 //!
 //! ## Modules
 //! There are two ways to encode and decode structs using `bincode`, either using `rustc_serialize`
@@ -38,29 +38,62 @@
 
 #![doc(html_logo_url = "./icon.png")]
 
-#[cfg(feature = "rustc-serialize")]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+// Lets `std::fmt`, `std::mem` etc. resolve to `core` without `std`.
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 extern crate rustc_serialize as rustc_serialize_crate;
 extern crate byteorder;
-extern crate num_traits;
 #[cfg(feature = "serde")]
 extern crate serde as serde_crate;
+#[cfg(feature = "std")]
 extern crate bit_vec;
 // extern crate bitpack as bit_pack;
-extern crate leb128;
-extern crate conv;
 extern crate half;
 
-pub use refbox::{RefBox, StrBox, SliceBox};
+pub use refbox::{RefBox, StrBox, SliceBox, RawValue};
+pub use niche::NonZeroOption;
+#[cfg(feature = "std")]
 pub use bitvec::{BVec, BitVec};
 // pub use bitpack::{BPack, BitPack};
 pub use float::FloatEncoding;
+pub use config::{Config, DecodeLimits, StructEncoding};
+pub use error::{Error, ErrorKind, ErrorPosition, Result};
+pub use io::{IoError, Reader, Writer};
+#[cfg(feature = "serde")]
+pub use serde::{Encoder, Decoder, SeqIter, StreamDeserializer};
+#[cfg(feature = "serde")]
+pub use lazy::Lazy;
 
 mod refbox;
+mod niche;
+#[cfg(feature = "std")]
 mod bitvec;
 // mod bitpack;
+#[cfg(feature = "serde")]
+mod lazy;
 mod float;
+mod varint;
+mod config;
+mod error;
+mod io;
+
+/// The `alloc` items that are in the prelude with `std`.
+#[cfg(not(feature = "std"))]
+mod prelude {
+    pub use alloc::boxed::Box;
+    pub use alloc::string::{String, ToString};
+    pub use alloc::vec::Vec;
+}
 
-#[cfg(feature = "rustc-serialize")]
+// The `rustc_serialize` backend needs `std`.
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 pub mod rustc_serialize;
 #[cfg(feature = "serde")]
 pub mod serde;
@@ -0,0 +1,101 @@
+use std::num::{NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU128, NonZeroUsize,
+               NonZeroI8, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI128, NonZeroIsize};
+
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
+use rustc_serialize_crate::{Encodable, Encoder, Decodable, Decoder};
+#[cfg(feature = "serde")]
+use serde_crate as serde;
+
+/// An `Option` of a `NonZero*` integer that is encoded as a single integer,
+/// with `0` meaning `None`.
+///
+/// A plain `Option` is encoded with a tag byte in front of its value, but
+/// the integer of a `NonZeroU32` is never `0`, so `NonZeroOption` uses `0`
+/// for `None` and saves the tag. `NonZeroOption(None)` takes one byte, and
+/// `NonZeroOption(Some(v))` as many as `v.get()` does.
+///
+/// The `rustc_serialize` backend supports all but the 128-bit integers.
+///
+/// ```rust
+/// # extern crate mincode;
+/// use std::num::NonZeroU32;
+/// use mincode::{NonZeroOption, FloatEncoding, SizeLimit};
+/// use mincode::serde::{serialize, deserialize};
+///
+/// # fn main() {
+/// let id = NonZeroOption(NonZeroU32::new(300));
+/// let bytes = serialize(&id, SizeLimit::Infinite, FloatEncoding::Normal).unwrap();
+/// assert_eq!(bytes.len(), 2);
+/// assert_eq!(deserialize::<NonZeroOption<NonZeroU32>, _>(&bytes, FloatEncoding::Normal).unwrap(), id);
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct NonZeroOption<T>(pub Option<T>);
+
+impl<T> From<Option<T>> for NonZeroOption<T> {
+    fn from(value: Option<T>) -> NonZeroOption<T> {
+        NonZeroOption(value)
+    }
+}
+
+impl<T> NonZeroOption<T> {
+    /// Returns the wrapped `Option`.
+    pub fn into_inner(self) -> Option<T> {
+        self.0
+    }
+}
+
+macro_rules! impl_non_zero_option {
+    ($non_zero:ident, $int:ident) => {
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for NonZeroOption<$non_zero> {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                where S: serde::Serializer
+            {
+                let value: $int = self.0.map_or(0, $non_zero::get);
+                serde::Serialize::serialize(&value, serializer)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> serde::Deserialize<'de> for NonZeroOption<$non_zero> {
+            fn deserialize<D>(deserializer: D) -> Result<NonZeroOption<$non_zero>, D::Error>
+                where D: serde::Deserializer<'de>
+            {
+                let value: $int = try!(serde::Deserialize::deserialize(deserializer));
+                Ok(NonZeroOption($non_zero::new(value)))
+            }
+        }
+    };
+    ($non_zero:ident, $int:ident, $emit:ident, $read:ident) => {
+        impl_non_zero_option!($non_zero, $int);
+
+        #[cfg(all(feature = "rustc-serialize", feature = "std"))]
+        impl Encodable for NonZeroOption<$non_zero> {
+            fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+                s.$emit(self.0.map_or(0, $non_zero::get))
+            }
+        }
+
+        #[cfg(all(feature = "rustc-serialize", feature = "std"))]
+        impl Decodable for NonZeroOption<$non_zero> {
+            fn decode<D: Decoder>(d: &mut D) -> Result<NonZeroOption<$non_zero>, D::Error> {
+                let value = try!(d.$read());
+                Ok(NonZeroOption($non_zero::new(value)))
+            }
+        }
+    };
+}
+
+impl_non_zero_option!(NonZeroU8, u8, emit_u8, read_u8);
+impl_non_zero_option!(NonZeroU16, u16, emit_u16, read_u16);
+impl_non_zero_option!(NonZeroU32, u32, emit_u32, read_u32);
+impl_non_zero_option!(NonZeroU64, u64, emit_u64, read_u64);
+impl_non_zero_option!(NonZeroU128, u128);
+impl_non_zero_option!(NonZeroUsize, usize, emit_usize, read_usize);
+impl_non_zero_option!(NonZeroI8, i8, emit_i8, read_i8);
+impl_non_zero_option!(NonZeroI16, i16, emit_i16, read_i16);
+impl_non_zero_option!(NonZeroI32, i32, emit_i32, read_i32);
+impl_non_zero_option!(NonZeroI64, i64, emit_i64, read_i64);
+impl_non_zero_option!(NonZeroI128, i128);
+impl_non_zero_option!(NonZeroIsize, isize, emit_isize, read_isize);
@@ -1,11 +1,16 @@
-use std::boxed::Box;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 
-#[cfg(feature = "rustc-serialize")]
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 use rustc_serialize_crate::{Encodable, Encoder, Decodable, Decoder};
 
 #[cfg(feature = "serde")]
 use serde_crate as serde;
+#[cfg(feature = "serde")]
+use ::serde::BytesVisitor;
 
 /// A struct for encoding nested reference types.
 ///
@@ -76,6 +81,33 @@ pub struct SliceBox<'a, T: 'a> {
     inner: RefBoxInner<'a, [T], Vec<T>>
 }
 
+/// Bytes that are already encoded, embedded into a value without being decoded.
+///
+/// A `RawValue` is encoded like a `[u8]`, its length followed by the bytes exactly
+/// as provided, so a relay can forward the encoding of a value it never looks at.
+/// Like a `SliceBox` it borrows the bytes for encoding; decoding copies them out
+/// of the input into an owned `RawValue`, without decoding their content.
+///
+/// Decoding copies even when the input is a slice: the decoders read from a
+/// `Reader`, which can not lend out its bytes, so a `RawValue` never borrows
+/// from the input it was decoded from.
+///
+/// ```rust
+/// use mincode::{RawValue, SizeLimit, FloatEncoding};
+/// use mincode::serde::{serialize, deserialize};
+///
+/// let blob = serialize(&("opaque", 42u32), SizeLimit::Infinite, FloatEncoding::Normal).unwrap();
+/// let envelope = serialize(&(7u8, RawValue::new(&blob)), SizeLimit::Infinite, FloatEncoding::Normal).unwrap();
+///
+/// let (to, payload): (u8, RawValue) = deserialize(&envelope, FloatEncoding::Normal).unwrap();
+/// assert_eq!(to, 7);
+/// assert_eq!(&payload[..], &blob[..]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RawValue<'a> {
+    inner: RefBoxInner<'a, [u8], Vec<u8>>
+}
+
 #[derive(Debug, PartialEq, PartialOrd, Eq, Ord, Hash)]
 enum RefBoxInner<'a, A: 'a + ?Sized, B> {
     Ref(&'a A),
@@ -141,14 +173,14 @@ impl <T> RefBox<'static, T>  {
     }
 }
 
-#[cfg(feature = "rustc-serialize")]
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 impl <'a, T: Encodable> Encodable for RefBox<'a, T> {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
         self.inner.encode(s)
     }
 }
 
-#[cfg(feature = "rustc-serialize")]
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 impl <T: Decodable> Decodable for RefBox<'static, T> {
     fn decode<D: Decoder>(d: &mut D) -> Result<RefBox<'static, T>, D::Error> {
         let inner = try!(Decodable::decode(d));
@@ -160,7 +192,7 @@ impl <T: Decodable> Decodable for RefBox<'static, T> {
 impl<'a, T> serde::Serialize for RefBox<'a, T>
     where T: serde::Serialize,
 {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: serde::Serializer
     {
         serde::Serialize::serialize(&self.inner, serializer)
@@ -168,9 +200,9 @@ impl<'a, T> serde::Serialize for RefBox<'a, T>
 }
 
 #[cfg(feature = "serde")]
-impl<'a, T: serde::Deserialize> serde::Deserialize for RefBox<'a, T> {
-    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error>
-        where D: serde::Deserializer
+impl<'de, 'a, T: serde::Deserialize<'de>> serde::Deserialize<'de> for RefBox<'a, T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
     {
         let inner = try!(serde::Deserialize::deserialize(deserializer));
         Ok(RefBox{ inner: inner })
@@ -239,14 +271,14 @@ impl StrBox<'static>  {
     }
 }
 
-#[cfg(feature = "rustc-serialize")]
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 impl <'a> Encodable for StrBox<'a> {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
         self.inner.encode(s)
     }
 }
 
-#[cfg(feature = "rustc-serialize")]
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 impl Decodable for StrBox<'static> {
     fn decode<D: Decoder>(d: &mut D) -> Result<StrBox<'static>, D::Error> {
         let inner: RefBoxInner<'static, str, String> = try!(Decodable::decode(d));
@@ -256,7 +288,7 @@ impl Decodable for StrBox<'static> {
 
 #[cfg(feature = "serde")]
 impl<'a> serde::Serialize for StrBox<'a> {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: serde::Serializer
     {
         serde::Serialize::serialize(&self.inner, serializer)
@@ -264,9 +296,9 @@ impl<'a> serde::Serialize for StrBox<'a> {
 }
 
 #[cfg(feature = "serde")]
-impl serde::Deserialize for StrBox<'static> {
-    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error>
-        where D: serde::Deserializer
+impl<'de> serde::Deserialize<'de> for StrBox<'static> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
     {
         let inner = try!(serde::Deserialize::deserialize(deserializer));
         Ok(StrBox{ inner: inner })
@@ -330,14 +362,14 @@ impl <T> SliceBox<'static, T>  {
     }
 }
 
-#[cfg(feature = "rustc-serialize")]
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 impl <'a, T: Encodable> Encodable for SliceBox<'a, T> {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
         self.inner.encode(s)
     }
 }
 
-#[cfg(feature = "rustc-serialize")]
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 impl <T: Decodable> Decodable for SliceBox<'static, T> {
     fn decode<D: Decoder>(d: &mut D) -> Result<SliceBox<'static, T>, D::Error> {
         let inner: RefBoxInner<'static, [T], Vec<T>> = try!(Decodable::decode(d));
@@ -349,7 +381,7 @@ impl <T: Decodable> Decodable for SliceBox<'static, T> {
 impl<'a, T> serde::Serialize for SliceBox<'a, T>
     where T: serde::Serialize,
 {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: serde::Serializer
     {
         serde::Serialize::serialize(&self.inner, serializer)
@@ -357,16 +389,98 @@ impl<'a, T> serde::Serialize for SliceBox<'a, T>
 }
 
 #[cfg(feature = "serde")]
-impl<'a, T: serde::Deserialize> serde::Deserialize for SliceBox<'a, T> {
-    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error>
-        where D: serde::Deserializer
+impl<'de, 'a, T: serde::Deserialize<'de>> serde::Deserialize<'de> for SliceBox<'a, T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
     {
         let inner = try!(serde::Deserialize::deserialize(deserializer));
         Ok(SliceBox{ inner: inner })
     }
 }
 
-#[cfg(feature = "rustc-serialize")]
+//
+// RawValue
+//
+
+impl<'a> RawValue<'a> {
+    /// Creates a new RawValue that looks at borrowed bytes.
+    pub fn new(bytes: &'a [u8]) -> RawValue<'a> {
+        RawValue {
+            inner: RefBoxInner::Ref(bytes)
+        }
+    }
+
+    /// Extract the bytes from a `RawValue`.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.inner {
+            RefBoxInner::Ref(s) => s.to_vec(),
+            RefBoxInner::Box(s) => s
+        }
+    }
+
+    /// Convert to an Owned `RawValue`.
+    pub fn to_owned(self) -> RawValue<'static> {
+        RawValue::boxed(self.into_vec())
+    }
+}
+
+// Borrowed and owned bytes are the same value.
+impl<'a, 'b> PartialEq<RawValue<'b>> for RawValue<'a> {
+    fn eq(&self, other: &RawValue<'b>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<'a> Eq for RawValue<'a> {}
+
+impl<'a> Hash for RawValue<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self[..].hash(state)
+    }
+}
+
+impl RawValue<'static> {
+    /// Creates a new RawValue that owns its bytes.
+    pub fn boxed(bytes: Vec<u8>) -> RawValue<'static> {
+        RawValue { inner: RefBoxInner::Box(bytes) }
+    }
+}
+
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
+impl<'a> Encodable for RawValue<'a> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.inner.encode(s)
+    }
+}
+
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
+impl Decodable for RawValue<'static> {
+    fn decode<D: Decoder>(d: &mut D) -> Result<RawValue<'static>, D::Error> {
+        let inner: RefBoxInner<'static, [u8], Vec<u8>> = try!(Decodable::decode(d));
+        Ok(RawValue{inner: inner})
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a> serde::Serialize for RawValue<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        serializer.serialize_bytes(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, 'a> serde::Deserialize<'de> for RawValue<'a> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        let bytes = try!(deserializer.deserialize_byte_buf(BytesVisitor));
+        Ok(RawValue{ inner: RefBoxInner::Box(bytes) })
+    }
+}
+
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 impl <'a, A: Encodable + ?Sized, B: Encodable> Encodable for RefBoxInner<'a, A, B> {
     fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
         match self {
@@ -381,7 +495,7 @@ impl<'a, A: ?Sized, B> serde::Serialize for RefBoxInner<'a, A, B>
     where A: serde::Serialize,
           B: serde::Serialize,
 {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: serde::Serializer
     {
         match self {
@@ -391,7 +505,7 @@ impl<'a, A: ?Sized, B> serde::Serialize for RefBoxInner<'a, A, B>
     }
 }
 
-#[cfg(feature = "rustc-serialize")]
+#[cfg(all(feature = "rustc-serialize", feature = "std"))]
 impl <A: ?Sized, B: Decodable> Decodable for RefBoxInner<'static, A, B> {
     fn decode<D: Decoder>(d: &mut D) -> Result<RefBoxInner<'static, A, B>, D::Error> {
         let decoded = try!(Decodable::decode(d));
@@ -400,11 +514,11 @@ impl <A: ?Sized, B: Decodable> Decodable for RefBoxInner<'static, A, B> {
 }
 
 #[cfg(feature = "serde")]
-impl<'a, A: ?Sized, B> serde::Deserialize for RefBoxInner<'a, A, B>
-    where B: serde::Deserialize,
+impl<'de, 'a, A: ?Sized, B> serde::Deserialize<'de> for RefBoxInner<'a, A, B>
+    where B: serde::Deserialize<'de>,
 {
-    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error>
-        where D: serde::Deserializer
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>
     {
         let deserialized = try!(serde::Deserialize::deserialize(deserializer));
         Ok(RefBoxInner::Box(deserialized))
@@ -432,3 +546,14 @@ impl <'a, T> Deref for SliceBox<'a, T> {
         }
     }
 }
+
+impl<'a> Deref for RawValue<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.inner {
+            RefBoxInner::Ref(t) => t,
+            RefBoxInner::Box(ref b) => b.deref()
+        }
+    }
+}
@@ -3,8 +3,8 @@
 //! implementation.
 
 use rustc_serialize_crate::{Encodable, Decodable};
-use std::io::{Write, Read};
-use ::{SizeLimit, FloatEncoding};
+use io::{Reader, Writer};
+use ::{SizeLimit, DecodeLimits, FloatEncoding};
 
 pub use self::writer::{SizeChecker, EncoderWriter, EncodingResult, EncodingError};
 pub use self::reader::{DecoderReader, DecodingResult, DecodingError, InvalidEncoding};
@@ -17,30 +17,76 @@ mod writer;
 /// If the encoding would take more bytes than allowed by `size_limit`,
 /// an error is returned.
 pub fn encode<T: Encodable>(t: &T, size_limit: SizeLimit, float_enc: FloatEncoding) -> EncodingResult<Vec<u8>> {
-    // Since we are putting values directly into a vector, we can do size
-    // computation out here and pre-allocate a buffer of *exactly*
-    // the right size.
-    let mut w = if let SizeLimit::Bounded(l) = size_limit {
-        let actual_size = encoded_size_bounded(t, l, float_enc);
-        let actual_size = try!(actual_size.ok_or(EncodingError::SizeLimit));
-        Vec::with_capacity(actual_size as usize)
-    } else {
-        vec![]
+    let limit = match size_limit {
+        SizeLimit::Bounded(x) => x,
+        SizeLimit::Infinite => u64::MAX,
     };
+    // The size limit is checked while writing, a value that is too big
+    // is only encoded up to the limit.
+    let mut w = vec![];
+    try!(encode_bounded(t, &mut w, limit, float_enc));
+    Ok(w)
+}
 
-    match encode_into(t, &mut w, SizeLimit::Infinite, float_enc) {
-        Ok(()) => Ok(w),
-        Err(e) => Err(e)
+/// Encodes an object into a slice and returns the number of bytes written.
+///
+/// If the object does not fit, a `BufferTooSmall` error with the encoded size of
+/// the object is returned and the contents of the slice are unspecified.
+pub fn encode_into_slice<T: Encodable>(buffer: &mut [u8], t: &T, float_enc: FloatEncoding) -> EncodingResult<usize> {
+    let limit = buffer.len() as u64;
+    let mut writer = buffer;
+    let mut encoder = writer::EncoderWriter::bounded(&mut writer, limit, float_enc);
+    match t.encode(&mut encoder) {
+        Ok(()) => Ok(encoder.bytes_written() as usize),
+        Err(err) => {
+            let err = err.locate(encoder.bytes_written(), None);
+            if let EncodingError::At(ref inner, _) = err {
+                if let EncodingError::SizeLimit = **inner {
+                    return Err(EncodingError::BufferTooSmall {
+                        required: encoded_size(t, float_enc),
+                    });
+                }
+            }
+            Err(err)
+        }
     }
 }
 
 /// Decodes a slice of bytes into an object.
 ///
-/// This method does not have a size-limit because if you already have the bytes
-/// in memory, then you don't gain anything by having a limiter.
+/// The length of the slice is the size limit, so reading past the end of the
+/// slice fails with `SizeLimit`.
 pub fn decode<T: Decodable>(b: &[u8], float_enc: FloatEncoding) -> DecodingResult<T> {
+    let size_limit = SizeLimit::Bounded(b.len() as u64);
     let mut b = b;
-    decode_from(&mut b, SizeLimit::Infinite, float_enc)
+    decode_from(&mut b, size_limit, float_enc)
+}
+
+/// Decodes an object from the start of a slice of bytes, and returns it
+/// together with the number of bytes it was decoded from.
+///
+/// The length of the slice is the size limit, like in `decode`.
+pub fn decode_with_len<T: Decodable>(b: &[u8], float_enc: FloatEncoding) -> DecodingResult<(T, usize)> {
+    let mut reader = b;
+    let size_limit = SizeLimit::Bounded(b.len() as u64);
+    let mut decoder = reader::DecoderReader::new(&mut reader, size_limit, float_enc);
+    let value = try!(Decodable::decode(&mut decoder).map_err(|err| err.locate(decoder.bytes_read(), None)));
+    Ok((value, decoder.bytes_read() as usize))
+}
+
+/// Decodes a slice of bytes into an object, failing with `InvalidEncoding` if
+/// any bytes are left after it.
+pub fn decode_exact<T: Decodable>(b: &[u8], float_enc: FloatEncoding) -> DecodingResult<T> {
+    let (value, len) = try!(decode_with_len(b, float_enc));
+    if len == b.len() {
+        Ok(value)
+    } else {
+        let err = DecodingError::InvalidEncoding(InvalidEncoding {
+            desc: "trailing bytes after the value",
+            detail: Some(format!("Expected {} bytes, got {}", len, b.len()))
+        });
+        Err(err.locate(len as u64, None))
+    }
 }
 
 /// Encodes an object directly into a `Writer`.
@@ -51,20 +97,24 @@ pub fn decode<T: Decodable>(b: &[u8], float_enc: FloatEncoding) -> DecodingResul
 /// If this returns an `EncodingError` (other than SizeLimit), assume that the
 /// writer is in an invalid state, as writing could bail out in the middle of
 /// encoding.
-pub fn encode_into<T: Encodable, W: Write>(t: &T,
+pub fn encode_into<T: Encodable, W: Writer>(t: &T,
                                            w: &mut W,
                                            size_limit: SizeLimit,
                                            float_enc: FloatEncoding)
                                            -> EncodingResult<()> {
     match size_limit {
-        SizeLimit::Infinite => (),
-        SizeLimit::Bounded(x) => {
-            let mut size_checker = SizeChecker::new(x, float_enc);
-            t.encode(&mut size_checker)?;
+        SizeLimit::Infinite => encode_bounded(t, w, u64::MAX, float_enc),
+        SizeLimit::Bounded(_) => {
+            // The value is buffered, so that nothing is written if it is too big.
+            let buffer = try!(encode(t, size_limit, float_enc));
+            w.write_all(&buffer).map_err(EncodingError::IoError)
         }
     }
+}
 
-    t.encode(&mut writer::EncoderWriter::new(w, float_enc))
+fn encode_bounded<T: Encodable, W: Writer>(t: &T, w: &mut W, limit: u64, float_enc: FloatEncoding) -> EncodingResult<()> {
+    let mut encoder = writer::EncoderWriter::bounded(w, limit, float_enc);
+    t.encode(&mut encoder).map_err(|err| err.locate(encoder.bytes_written(), None))
 }
 
 /// Decoes an object directly from a `Buffer`ed Reader.
@@ -76,11 +126,30 @@ pub fn encode_into<T: Encodable, W: Write>(t: &T,
 /// If this returns an `DecodingError`, assume that the buffer that you passed
 /// in is in an invalid state, as the error could be returned during any point
 /// in the reading.
-pub fn decode_from<R: Read, T: Decodable>(r: &mut R, size_limit: SizeLimit, float_enc: FloatEncoding) -> DecodingResult<T> {
-    Decodable::decode(&mut reader::DecoderReader::new(r, size_limit, float_enc))
+pub fn decode_from<R: Reader, T: Decodable>(r: &mut R, size_limit: SizeLimit, float_enc: FloatEncoding) -> DecodingResult<T> {
+    let mut decoder = reader::DecoderReader::new(r, size_limit, float_enc);
+    Decodable::decode(&mut decoder).map_err(|err| err.locate(decoder.bytes_read(), None))
 }
 
 
+/// Decodes an object from a `Reader` that may be untrusted.
+///
+/// Like `decode_from`, but lengths in the input that claim more than
+/// `limits` allow fail with `SizeLimit` before anything is allocated for them.
+pub fn decode_from_with_limits<R: Reader, T: Decodable>(r: &mut R, size_limit: SizeLimit, limits: DecodeLimits, float_enc: FloatEncoding) -> DecodingResult<T> {
+    let mut decoder = reader::DecoderReader::with_limits(r, size_limit, limits, float_enc);
+    Decodable::decode(&mut decoder).map_err(|err| err.locate(decoder.bytes_read(), None))
+}
+
+/// Decodes a slice of bytes that may be untrusted into an object.
+///
+/// The length of the slice is the size limit, reading past its end fails
+/// with `SizeLimit`.
+pub fn decode_with_limits<T: Decodable>(b: &[u8], limits: DecodeLimits, float_enc: FloatEncoding) -> DecodingResult<T> {
+    let mut reader = b;
+    decode_from_with_limits(&mut reader, SizeLimit::Bounded(b.len() as u64), limits, float_enc)
+}
+
 /// Returns the size that an object would be if encoded using bincode.
 ///
 /// This is used internally as part of the check for encode_into, but it can
@@ -1,18 +1,14 @@
-use std::io::Read;
-use std::io::Error as IoError;
 use std::error::Error;
 use std::fmt;
-use std::convert::From;
+use std::convert::{From, TryFrom};
 
-use byteorder::ReadBytesExt;
-use num_traits;
 use rustc_serialize_crate::Decoder;
 
-use ::SizeLimit;
-
-use conv::*;
-use leb128;
+use ::{SizeLimit, DecodeLimits, ErrorPosition};
+use error::{ErrorKind, Segment};
+use io::{self, IoError, Reader};
 
+use varint;
 use float::*;
 
 #[derive(Eq, PartialEq, Clone, Debug)]
@@ -48,7 +44,12 @@ pub enum DecodingError {
     InvalidEncoding(InvalidEncoding),
     /// If decoding a message takes more than the provided size limit, this
     /// error is returned.
-    SizeLimit
+    SizeLimit,
+    /// If the decoded value is nested deeper than `DecodeLimits::max_depth`,
+    /// this error is returned.
+    DepthLimit,
+    /// Another error, together with where in the input it occurred.
+    At(Box<DecodingError>, ErrorPosition),
 }
 
 pub type DecodingResult<T> = Result<T, DecodingError>;
@@ -65,7 +66,11 @@ impl fmt::Display for DecodingError {
             DecodingError::InvalidEncoding(ref ib) =>
                 write!(fmt, "InvalidEncoding: {}", ib),
             DecodingError::SizeLimit =>
-                write!(fmt, "SizeLimit")
+                write!(fmt, "SizeLimit"),
+            DecodingError::DepthLimit =>
+                write!(fmt, "DepthLimit"),
+            DecodingError::At(ref err, ref position) =>
+                write!(fmt, "{} {}", err, position),
         }
     }
 }
@@ -75,7 +80,9 @@ impl Error for DecodingError {
         match *self {
             DecodingError::IoError(ref err) => Error::description(err),
             DecodingError::InvalidEncoding(ref ib) => ib.desc,
-            DecodingError::SizeLimit => "the size limit for decoding has been reached"
+            DecodingError::SizeLimit => "the size limit for decoding has been reached",
+            DecodingError::DepthLimit => "the depth limit for decoding has been reached",
+            DecodingError::At(ref err, _) => err.description(),
         }
     }
 
@@ -83,8 +90,41 @@ impl Error for DecodingError {
         match *self {
             DecodingError::IoError(ref err)     => err.cause(),
             DecodingError::InvalidEncoding(_) => None,
-            DecodingError::SizeLimit => None
+            DecodingError::SizeLimit => None,
+            DecodingError::DepthLimit => None,
+            DecodingError::At(ref err, _) => err.cause(),
+        }
+    }
+}
+
+impl DecodingError {
+    /// Returns where in the input the error occurred.
+    pub fn position(&self) -> Option<&ErrorPosition> {
+        match *self {
+            DecodingError::At(_, ref position) => Some(position),
+            _ => None,
+        }
+    }
+
+    /// Returns the error without its position.
+    pub fn into_inner(self) -> DecodingError {
+        match self {
+            DecodingError::At(err, _) => *err,
+            err => err,
+        }
+    }
+
+    /// Attaches the offset if the error has no position yet, and prepends
+    /// the segment to its path.
+    pub(crate) fn locate(self, offset: u64, segment: Option<Segment>) -> DecodingError {
+        let (err, mut position) = match self {
+            DecodingError::At(err, position) => (err, position),
+            err => (Box::new(err), ErrorPosition::new(offset)),
+        };
+        if let Some(segment) = segment {
+            position.push_front(segment);
         }
+        DecodingError::At(err, position)
     }
 }
 
@@ -94,6 +134,23 @@ impl From<IoError> for DecodingError {
     }
 }
 
+impl From<DecodingError> for ::Error {
+    fn from(err: DecodingError) -> ::Error {
+        let kind = match err {
+            DecodingError::IoError(err) => ErrorKind::Io(err),
+            DecodingError::InvalidEncoding(InvalidEncoding { desc, detail }) =>
+                ErrorKind::InvalidEncoding { desc: desc, detail: detail },
+            DecodingError::SizeLimit => ErrorKind::SizeLimit,
+            DecodingError::DepthLimit => ErrorKind::DepthLimit,
+            DecodingError::At(err, position) => {
+                let err = ::Error::from(*err);
+                return ::Error::new(err.into_kind(), Some(position));
+            }
+        };
+        ::Error::from(kind)
+    }
+}
+
 /// A Decoder that reads bytes from a buffer.
 ///
 /// This struct should rarely be used.
@@ -110,51 +167,88 @@ pub struct DecoderReader<'a, R: 'a> {
     read: u64,
     read_f32: FloatDecoder<f32>,
     read_f64: FloatDecoder<f64>,
+    float_size_f32: u64,
+    float_size_f64: u64,
+    limits: DecodeLimits,
+    /// What has been counted against `limits.max_alloc`.
+    allocated: u64,
+    /// How many values the one being read is nested in.
+    depth: u64,
 }
 
-impl<'a, R: Read> DecoderReader<'a, R> {
+impl<'a, R: Reader> DecoderReader<'a, R> {
     pub fn new(r: &'a mut R, size_limit: SizeLimit, float_enc: FloatEncoding) -> DecoderReader<'a, R> {
         let (read_f32, read_f64) = float_decoder(float_enc);
+        let (float_size_f32, float_size_f64) = float_sizes(float_enc);
         DecoderReader {
             reader: r,
             size_limit: size_limit,
             read: 0,
             read_f32: read_f32,
             read_f64: read_f64,
+            float_size_f32: float_size_f32 as u64,
+            float_size_f64: float_size_f64 as u64,
+            limits: DecodeLimits::default(),
+            allocated: 0,
+            depth: 0,
         }
     }
 
+    /// Creates a DecoderReader that fails with `SizeLimit` when the input
+    /// claims more than `limits` allow.
+    ///
+    /// Lengths are checked against `limits` before any element is decoded.
+    /// `rustc_serialize` preallocates at most 1 MiB for a `Vec` on its own,
+    /// `max_seq_len` and `max_alloc` bound what the elements of a bogus
+    /// length make it allocate beyond that.
+    pub fn with_limits(r: &'a mut R, size_limit: SizeLimit, limits: DecodeLimits, float_enc: FloatEncoding) -> DecoderReader<'a, R> {
+        let mut decoder = DecoderReader::new(r, size_limit, float_enc);
+        decoder.limits = limits;
+        decoder
+    }
+
     /// Returns the number of bytes read from the contained Reader.
     pub fn bytes_read(&self) -> u64 {
         self.read
     }
 
-    fn read_unsigned<T: ValueFrom<u64> + misc::Saturated + num_traits::Unsigned>(&mut self) -> DecodingResult<T>
-    where RangeErrorKind: From<<T as ValueFrom<u64>>::Err> {
-        let r = leb128::read::unsigned(&mut self.reader); //.map(|(v, n)| (v as usize, n));
-        self.map_leb128_result::<T, _>(r)
+    fn read_unsigned<T: TryFrom<u64>>(&mut self) -> DecodingResult<T> {
+        let r = varint::read_unsigned(self.reader);
+        self.map_varint_result::<T, _>(r)
     }
 
-    fn read_signed<T: ValueFrom<i64> + misc::Saturated + num_traits::Signed>(&mut self) -> DecodingResult<T>
-    where RangeErrorKind: From<<T as ValueFrom<i64>>::Err> {
-        let r = leb128::read::signed(&mut self.reader); //.map(|(v, n)| (v as isize, n));
-        self.map_leb128_result::<T, _>(r)
+    fn read_signed<T: TryFrom<i64>>(&mut self) -> DecodingResult<T> {
+        let r = varint::read_signed(self.reader);
+        self.map_varint_result::<T, _>(r)
     }
 
-    fn map_leb128_result<T: ValueFrom<U> + misc::Saturated, U: ValueFrom<u64>>(&mut self, r: Result<(U, usize), leb128::read::Error>) -> DecodingResult<T>
-    where RangeErrorKind: From<<T as ValueFrom<U>>::Err> {
+    /// Decodes a `u128`.
+    ///
+    /// `rustc_serialize::Decoder` has no method for 128-bit integers, so only
+    /// code that knows it is decoding with mincode can call this.
+    pub fn read_u128(&mut self) -> DecodingResult<u128> {
+        let r = varint::read_unsigned128(self.reader);
+        self.map_varint_result::<u128, _>(r)
+    }
+
+    /// Decodes an `i128`, see `read_u128`.
+    pub fn read_i128(&mut self) -> DecodingResult<i128> {
+        let r = varint::read_signed128(self.reader);
+        self.map_varint_result::<i128, _>(r)
+    }
+
+    fn map_varint_result<T: TryFrom<U>, U>(&mut self, r: Result<(U, usize), varint::ReadError>) -> DecodingResult<T> {
         match r {
             Ok((v, bytes_read)) => {
                 self.read_bytes(bytes_read as u64)?;
-                // Ok(v.value_into().unwrap_or_saturate())
-                match v.value_into() {
+                match T::try_from(v) {
                     Ok(v) => Ok(v),
                     Err(_) => Err(DecodingError::SizeLimit)
                 }
             }
             Err(e) => Err(match e {
-                leb128::read::Error::IoError(e) => DecodingError::IoError(e),
-                leb128::read::Error::Overflow => DecodingError::SizeLimit
+                varint::ReadError::IoError(e) => DecodingError::IoError(e),
+                varint::ReadError::Overflow => DecodingError::SizeLimit
             })
         }
     }
@@ -174,13 +268,38 @@ impl <'a, A> DecoderReader<'a, A> {
         }
     }
 
+    /// Counts a length read from the input against the limits, `max` is the
+    /// limit for a single one of its kind.
+    fn charge(&mut self, len: u64, max: u64) -> Result<(), DecodingError> {
+        match self.limits.charge(self.allocated, len, max) {
+            Some(allocated) => {
+                self.allocated = allocated;
+                Ok(())
+            }
+            None => Err(DecodingError::SizeLimit),
+        }
+    }
+
+    /// Reads a value that is nested one level deeper than the current one.
+    fn nested<T, F>(&mut self, f: F) -> Result<T, DecodingError>
+        where F: FnOnce(&mut Self) -> Result<T, DecodingError>
+    {
+        if self.depth >= self.limits.max_depth {
+            return Err(DecodingError::DepthLimit);
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
     /*fn read_type<T>(&mut self) -> Result<(), DecodingError> {
         use std::mem::size_of;
         self.read_bytes(size_of::<T>() as u64)
     }*/
 }
 
-impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
+impl<'a, R: Reader> Decoder for DecoderReader<'a, R> {
     type Error = DecodingError;
 
     fn read_nil(&mut self) -> DecodingResult<()> {
@@ -200,7 +319,7 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
     }
     fn read_u8(&mut self) -> DecodingResult<u8> {
         self.read_bytes(1)?;
-        self.reader.read_u8().map_err(wrap_io)
+        io::read_u8(self.reader).map_err(wrap_io)
     }
     fn read_isize(&mut self) -> DecodingResult<isize> {
         self.read_signed::<_>()
@@ -216,7 +335,7 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
     }
     fn read_i8(&mut self) -> DecodingResult<i8> {
         self.read_bytes(1)?;
-        self.reader.read_i8().map_err(wrap_io)
+        io::read_u8(self.reader).map(|v| v as i8).map_err(wrap_io)
     }
     fn read_bool(&mut self) -> DecodingResult<bool> {
         let x = self.read_i8()?;
@@ -231,11 +350,15 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
     }
     fn read_f64(&mut self) -> DecodingResult<f64> {
         // self.reader.read_f64::<BigEndian>().map_err(wrap_io)
-        (self.read_f64)(&mut self.reader).map_err(wrap_io)
+        let bytes = self.float_size_f64;
+        self.read_bytes(bytes)?;
+        (self.read_f64)(self.reader).map_err(wrap_io)
     }
     fn read_f32(&mut self) -> DecodingResult<f32> {
         // self.reader.read_f32::<BigEndian>().map_err(wrap_io)
-        (self.read_f32)(&mut self.reader).map_err(wrap_io)
+        let bytes = self.float_size_f32;
+        self.read_bytes(bytes)?;
+        (self.read_f32)(self.reader).map_err(wrap_io)
     }
     fn read_char(&mut self) -> DecodingResult<char> {
         use std::str;
@@ -245,40 +368,29 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
             detail: None
         });
 
-        let mut buf = [0];
-
-        let _ = try!(self.reader.read(&mut buf[..]));
-        let first_byte = buf[0];
+        try!(self.read_bytes(1));
+        let first_byte = try!(io::read_u8(self.reader));
         let width = utf8_char_width(first_byte);
         if width == 1 { return Ok(first_byte as char) }
         if width == 0 { return Err(error)}
 
         let mut buf = [first_byte, 0, 0, 0];
-        {
-            let mut start = 1;
-            while start < width {
-                match try!(self.reader.read(&mut buf[start .. width])) {
-                    n if n == width - start => break,
-                    n if n < width - start => { start += n; }
-                    _ => return Err(error)
-                }
-            }
-        }
+        try!(self.read_bytes(width as u64 - 1));
+        try!(self.reader.read_exact(&mut buf[1 .. width]));
 
-        let res = try!(match str::from_utf8(&buf[..width]).ok() {
+        match str::from_utf8(&buf[..width]).ok() {
             Some(s) => Ok(s.chars().next().unwrap()),
             None => Err(error)
-        });
-
-        try!(self.read_bytes(res.len_utf8() as u64));
-        Ok(res)
+        }
     }
 
     fn read_str(&mut self) -> DecodingResult<String> {
         let len = self.read_usize()?;
+        let max = self.limits.max_str_len;
+        try!(self.charge(len as u64, max));
+        try!(self.read_bytes(len as u64));
 
-        let mut buff = Vec::new();
-        try!(self.reader.by_ref().take(len as u64).read_to_end(&mut buff));
+        let buff = try!(io::read_to_vec(self.reader, len as u64));
         match String::from_utf8(buff) {
             Ok(s) => Ok(s),
             Err(err) => Err(DecodingError::InvalidEncoding(InvalidEncoding {
@@ -290,7 +402,7 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
     fn read_enum<T, F>(&mut self, _: &str, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        self.nested(f)
     }
     fn read_enum_variant<T, F>(&mut self, names: &[&str], mut f: F) -> DecodingResult<T>
         where F: FnMut(&mut DecoderReader<'a, R>, usize) -> DecodingResult<T>
@@ -305,10 +417,10 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
             f(self, id)
         }
     }
-    fn read_enum_variant_arg<T, F>(&mut self, _: usize, f: F) -> DecodingResult<T>
+    fn read_enum_variant_arg<T, F>(&mut self, a_idx: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.read, Some(Segment::Element(a_idx))))
     }
     fn read_enum_struct_variant<T, F>(&mut self, names: &[&str], f: F) -> DecodingResult<T>
         where F: FnMut(&mut DecoderReader<'a, R>, usize) -> DecodingResult<T>
@@ -316,33 +428,33 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
         self.read_enum_variant(names, f)
     }
     fn read_enum_struct_variant_field<T, F>(&mut self,
-                                            _: &str,
-                                            f_idx: usize,
+                                            f_name: &str,
+                                            _: usize,
                                             f: F)
                                             -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        self.read_enum_variant_arg(f_idx, f)
+        f(self).map_err(|err| err.locate(self.read, Some(Segment::Field(f_name))))
     }
-    fn read_struct<T, F>(&mut self, _: &str, _: usize, f: F) -> DecodingResult<T>
+    fn read_struct<T, F>(&mut self, s_name: &str, _: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        self.nested(f).map_err(|err| err.locate(self.read, Some(Segment::Struct(s_name))))
     }
-    fn read_struct_field<T, F>(&mut self, _: &str, _: usize, f: F) -> DecodingResult<T>
+    fn read_struct_field<T, F>(&mut self, f_name: &str, _: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.read, Some(Segment::Field(f_name))))
     }
     fn read_tuple<T, F>(&mut self, _: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        self.nested(f)
     }
-    fn read_tuple_arg<T, F>(&mut self, _: usize, f: F) -> DecodingResult<T>
+    fn read_tuple_arg<T, F>(&mut self, a_idx: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.read, Some(Segment::Element(a_idx))))
     }
     fn read_tuple_struct<T, F>(&mut self, _: &str, len: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
@@ -359,7 +471,7 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
     {
         let x = self.read_u8()?;
         match x {
-                1 => f(self, true),
+                1 => self.nested(|decoder| f(decoder, true)),
                 0 => f(self, false),
                 _ => Err(DecodingError::InvalidEncoding(InvalidEncoding {
                     desc: "invalid tag when decoding Option",
@@ -371,28 +483,32 @@ impl<'a, R: Read> Decoder for DecoderReader<'a, R> {
         where F: FnOnce(&mut DecoderReader<'a, R>, usize) -> DecodingResult<T>
     {
         let len = try!(self.read_usize());
-        f(self, len)
+        let max = self.limits.max_seq_len;
+        try!(self.charge(len as u64, max));
+        self.nested(|decoder| f(decoder, len))
     }
-    fn read_seq_elt<T, F>(&mut self, _: usize, f: F) -> DecodingResult<T>
+    fn read_seq_elt<T, F>(&mut self, idx: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.read, Some(Segment::Index(idx))))
     }
     fn read_map<T, F>(&mut self, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>, usize) -> DecodingResult<T>
     {
         let len = try!(self.read_usize());
-        f(self, len)
+        let max = self.limits.max_seq_len;
+        try!(self.charge(len as u64, max));
+        self.nested(|decoder| f(decoder, len))
     }
-    fn read_map_elt_key<T, F>(&mut self, _: usize, f: F) -> DecodingResult<T>
+    fn read_map_elt_key<T, F>(&mut self, idx: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.read, Some(Segment::Index(idx))))
     }
-    fn read_map_elt_val<T, F>(&mut self, _: usize, f: F) -> DecodingResult<T>
+    fn read_map_elt_val<T, F>(&mut self, idx: usize, f: F) -> DecodingResult<T>
         where F: FnOnce(&mut DecoderReader<'a, R>) -> DecodingResult<T>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.read, Some(Segment::Index(idx))))
     }
     fn error(&mut self, err: &str) -> DecodingError {
         DecodingError::InvalidEncoding(InvalidEncoding {
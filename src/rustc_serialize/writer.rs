@@ -1,15 +1,13 @@
-use std::io::Write;
-use std::io::Error as IoError;
 use std::error::Error;
 use std::fmt;
 
 use rustc_serialize_crate::Encoder;
 
-use byteorder::WriteBytesExt;
-
-use leb128;
-
 use float::*;
+use varint;
+use error::{ErrorKind, Segment};
+use io::{IoError, Writer};
+use ::ErrorPosition;
 
 pub type EncodingResult<T> = Result<T, EncodingError>;
 
@@ -24,6 +22,11 @@ pub enum EncodingError {
     /// This error is returned before any bytes are written to the
     /// output `Writer`.
     SizeLimit,
+    /// An object did not fit into the buffer it was encoded into, it needs
+    /// `required` bytes.
+    BufferTooSmall { required: u64 },
+    /// Another error, together with where in the output it occurred.
+    At(Box<EncodingError>, ErrorPosition),
 }
 
 /// An Encoder that encodes values directly into a Writer.
@@ -32,10 +35,18 @@ pub enum EncodingError {
 /// For most cases, prefer the `encode_into` function.
 pub struct EncoderWriter<'a, W: 'a> {
     writer: &'a mut W,
+    written: u64,
+    size_limit: u64,
     write_f32: FloatEncoder<f32>,
     write_f64: FloatEncoder<f64>,
+    float_size_f32: usize,
+    float_size_f64: usize,
 }
 
+/// An Encoder that only counts the bytes a value would be encoded to.
+///
+/// No bytes are encoded, so this is a cheap way to learn the encoded size
+/// of a value, see also `encoded_size`.
 pub struct SizeChecker {
     pub size_limit: u64,
     pub written: u64,
@@ -51,7 +62,10 @@ impl fmt::Display for EncodingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
             EncodingError::IoError(ref err) => write!(f, "IoError: {}", err),
-            EncodingError::SizeLimit => write!(f, "SizeLimit")
+            EncodingError::SizeLimit => write!(f, "SizeLimit"),
+            EncodingError::BufferTooSmall { required } =>
+                write!(f, "BufferTooSmall: {} bytes required", required),
+            EncodingError::At(ref err, ref position) => write!(f, "{} {}", err, position),
         }
     }
 }
@@ -60,34 +74,155 @@ impl Error for EncodingError {
     fn description(&self) -> &str {
         match *self {
             EncodingError::IoError(ref err) => Error::description(err),
-            EncodingError::SizeLimit => "the size limit for decoding has been reached"
+            EncodingError::SizeLimit => "the size limit for decoding has been reached",
+            EncodingError::BufferTooSmall { .. } => "the buffer is too small for the encoded object",
+            EncodingError::At(ref err, _) => err.description(),
         }
     }
 
     fn cause(&self) -> Option<&Error> {
         match *self {
             EncodingError::IoError(ref err)     => err.cause(),
-            EncodingError::SizeLimit => None
+            EncodingError::SizeLimit => None,
+            EncodingError::BufferTooSmall { .. } => None,
+            EncodingError::At(ref err, _) => err.cause(),
+        }
+    }
+}
+
+impl EncodingError {
+    /// Returns where in the output the error occurred.
+    pub fn position(&self) -> Option<&ErrorPosition> {
+        match *self {
+            EncodingError::At(_, ref position) => Some(position),
+            _ => None,
         }
     }
+
+    /// Returns the error without its position.
+    pub fn into_inner(self) -> EncodingError {
+        match self {
+            EncodingError::At(err, _) => *err,
+            err => err,
+        }
+    }
+
+    /// Attaches the offset if the error has no position yet, and prepends
+    /// the segment to its path.
+    pub(crate) fn locate(self, offset: u64, segment: Option<Segment>) -> EncodingError {
+        let (err, mut position) = match self {
+            EncodingError::At(err, position) => (err, position),
+            err => (Box::new(err), ErrorPosition::new(offset)),
+        };
+        if let Some(segment) = segment {
+            position.push_front(segment);
+        }
+        EncodingError::At(err, position)
+    }
+}
+
+impl From<EncodingError> for ::Error {
+    fn from(err: EncodingError) -> ::Error {
+        let kind = match err {
+            EncodingError::IoError(err) => ErrorKind::Io(err),
+            EncodingError::SizeLimit => ErrorKind::SizeLimit,
+            EncodingError::BufferTooSmall { required } => ErrorKind::BufferTooSmall { required: required },
+            EncodingError::At(err, position) => {
+                let err = ::Error::from(*err);
+                return ::Error::new(err.into_kind(), Some(position));
+            }
+        };
+        ::Error::from(kind)
+    }
 }
 
-impl <'a, W: Write> EncoderWriter<'a, W> {
+impl <'a, W: Writer> EncoderWriter<'a, W> {
     pub fn new(w: &'a mut W, float_enc: FloatEncoding) -> EncoderWriter<'a, W> {
         let (write_f32, write_f64) = float_encoder(float_enc);
+        let (float_size_f32, float_size_f64) = float_sizes(float_enc);
         EncoderWriter {
             writer: w,
+            written: 0,
+            size_limit: u64::MAX,
             write_f32: write_f32,
             write_f64: write_f64,
+            float_size_f32: float_size_f32,
+            float_size_f64: float_size_f64,
         }
     }
 
+    /// Creates an EncoderWriter that fails with `SizeLimit` as soon as more than
+    /// `limit` bytes would be written.
+    ///
+    /// The bytes before the limit was crossed have been written when that
+    /// happens, so write into a buffer if they must not reach their destination.
+    pub fn bounded(w: &'a mut W, limit: u64, float_enc: FloatEncoding) -> EncoderWriter<'a, W> {
+        let mut encoder = EncoderWriter::new(w, float_enc);
+        encoder.size_limit = limit;
+        encoder
+    }
+
+    /// Returns the number of bytes written to the contained Writer.
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// Checks that `size` more bytes fit into the size limit.
+    fn reserve(&mut self, size: usize) -> EncodingResult<()> {
+        if self.written + size as u64 <= self.size_limit {
+            Ok(())
+        } else {
+            Err(EncodingError::SizeLimit)
+        }
+    }
+
+    fn write_byte(&mut self, v: u8) -> EncodingResult<()> {
+        try!(self.reserve(1));
+        try!(self.writer.write_all(&[v]).map_err(wrap_io));
+        self.written += 1;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, v: &[u8]) -> EncodingResult<()> {
+        try!(self.reserve(v.len()));
+        try!(self.writer.write_all(v).map_err(wrap_io));
+        self.written += v.len() as u64;
+        Ok(())
+    }
+
     fn write_unsigned<T: Into<u64>>(&mut self, v: T) -> EncodingResult<()> {
-        leb128::write::unsigned(&mut self.writer, v.into()).map(|_| ()).map_err(wrap_io)
+        let v = v.into();
+        try!(self.reserve(varint::unsigned_size(v)));
+        let n = try!(varint::write_unsigned(self.writer, v).map_err(wrap_io));
+        self.written += n as u64;
+        Ok(())
     }
 
     fn write_signed<T: Into<i64>>(&mut self, v: T) -> EncodingResult<()> {
-        leb128::write::signed(&mut self.writer, v.into()).map(|_| ()).map_err(wrap_io)
+        let v = v.into();
+        try!(self.reserve(varint::signed_size(v)));
+        let n = try!(varint::write_signed(self.writer, v).map_err(wrap_io));
+        self.written += n as u64;
+        Ok(())
+    }
+
+    /// Encodes a `u128`.
+    ///
+    /// `rustc_serialize::Encoder` has no method for 128-bit integers, so only
+    /// code that knows it is encoding with mincode can call this.
+    pub fn emit_u128(&mut self, v: u128) -> EncodingResult<()> {
+        try!(self.reserve(varint::unsigned_size128(v)));
+        let n = try!(varint::write_unsigned128(self.writer, v).map_err(wrap_io));
+        self.written += n as u64;
+        Ok(())
+    }
+
+    /// Encodes an `i128`, see `emit_u128`.
+    pub fn emit_i128(&mut self, v: i128) -> EncodingResult<()> {
+        try!(self.reserve(varint::signed_size128(v)));
+        let n = try!(varint::write_signed128(self.writer, v).map_err(wrap_io));
+        self.written += n as u64;
+        Ok(())
     }
 }
 
@@ -103,8 +238,9 @@ impl SizeChecker {
     }
 
     fn add_raw(&mut self, size: usize) -> EncodingResult<()> {
-        self.written += size as u64;
-        if self.written <= self.size_limit {
+        // Only count the value if it fits, so that errors point at its start.
+        if self.written + size as u64 <= self.size_limit {
+            self.written += size as u64;
             Ok(())
         } else {
             Err(EncodingError::SizeLimit)
@@ -117,23 +253,25 @@ impl SizeChecker {
     }*/
 
     fn add_value_unsigned<T: Into<u64>>(&mut self, t: T) -> EncodingResult<()> {
-        let mut v: Vec<u8> = vec![];
-        match leb128::write::unsigned(&mut v, t.into()) {
-            Ok(n) => self.add_raw(n),
-            Err(e) => Err(wrap_io(e))
-        }
+        self.add_raw(varint::unsigned_size(t.into()))
     }
 
     fn add_value_signed<T: Into<i64>>(&mut self, t: T) -> EncodingResult<()> {
-        let mut v: Vec<u8> = vec![];
-        match leb128::write::signed(&mut v, t.into()) {
-            Ok(n) => self.add_raw(n),
-            Err(e) => Err(wrap_io(e))
-        }
+        self.add_raw(varint::signed_size(t.into()))
+    }
+
+    /// Counts a `u128`, see `EncoderWriter::emit_u128`.
+    pub fn emit_u128(&mut self, v: u128) -> EncodingResult<()> {
+        self.add_raw(varint::unsigned_size128(v))
+    }
+
+    /// Counts an `i128`, see `EncoderWriter::emit_u128`.
+    pub fn emit_i128(&mut self, v: i128) -> EncodingResult<()> {
+        self.add_raw(varint::signed_size128(v))
     }
 }
 
-impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
+impl<'a, W: Writer> Encoder for EncoderWriter<'a, W> {
     type Error = EncodingError;
 
     fn emit_nil(&mut self) -> EncodingResult<()> {
@@ -152,7 +290,7 @@ impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
         self.write_unsigned(v)
     }
     fn emit_u8(&mut self, v: u8) -> EncodingResult<()> {
-        self.writer.write_u8(v).map_err(wrap_io)
+        self.write_byte(v)
     }
     fn emit_isize(&mut self, v: isize) -> EncodingResult<()> {
         self.write_signed(v as i64)
@@ -167,18 +305,26 @@ impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
         self.write_signed(v)
     }
     fn emit_i8(&mut self, v: i8) -> EncodingResult<()> {
-        self.writer.write_i8(v).map_err(wrap_io)
+        self.write_byte(v as u8)
     }
     fn emit_bool(&mut self, v: bool) -> EncodingResult<()> {
-        self.writer.write_u8(if v {1} else {0}).map_err(wrap_io)
+        self.write_byte(if v {1} else {0})
     }
     fn emit_f64(&mut self, v: f64) -> EncodingResult<()> {
         //self.writer.write_f64::<BigEndian>(v).map_err(wrap_io)
-        (self.write_f64)(&mut self.writer, v).map_err(wrap_io)
+        let size = self.float_size_f64;
+        try!(self.reserve(size));
+        try!((self.write_f64)(self.writer, v).map_err(wrap_io));
+        self.written += self.float_size_f64 as u64;
+        Ok(())
     }
     fn emit_f32(&mut self, v: f32) -> EncodingResult<()> {
         //self.writer.write_f32::<BigEndian>(v).map_err(wrap_io)
-        (self.write_f32)(&mut self.writer, v).map_err(wrap_io)
+        let size = self.float_size_f32;
+        try!(self.reserve(size));
+        try!((self.write_f32)(self.writer, v).map_err(wrap_io));
+        self.written += self.float_size_f32 as u64;
+        Ok(())
     }
     fn emit_char(&mut self, v: char) -> EncodingResult<()> {
         // TODO: change this back once unicode works
@@ -189,11 +335,11 @@ impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
 
         let mut inter = String::with_capacity(1);
         inter.push(v);
-        self.writer.write_all(inter.as_bytes()).map_err(EncodingError::IoError)
+        self.write_bytes(inter.as_bytes())
     }
     fn emit_str(&mut self, v: &str) -> EncodingResult<()> {
         try!(self.emit_usize(v.len()));
-        self.writer.write_all(v.as_bytes()).map_err(EncodingError::IoError)
+        self.write_bytes(v.as_bytes())
     }
     fn emit_enum<F>(&mut self, __: &str, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
@@ -206,10 +352,10 @@ impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
         try!(self.write_unsigned(v_id as u64));
         f(self)
     }
-    fn emit_enum_variant_arg<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_enum_variant_arg<F>(&mut self, a_idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Element(a_idx))))
     }
     fn emit_enum_struct_variant<F>(&mut self,
                                    _: &str,
@@ -221,30 +367,30 @@ impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
     {
         f(self)
     }
-    fn emit_enum_struct_variant_field<F>(&mut self, _: &str, _: usize, f: F) -> EncodingResult<()>
+    fn emit_enum_struct_variant_field<F>(&mut self, f_name: &str, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Field(f_name))))
     }
-    fn emit_struct<F>(&mut self, _: &str, _: usize, f: F) -> EncodingResult<()>
+    fn emit_struct<F>(&mut self, name: &str, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Struct(name))))
     }
-    fn emit_struct_field<F>(&mut self, _: &str, _: usize, f: F) -> EncodingResult<()>
+    fn emit_struct_field<F>(&mut self, f_name: &str, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Field(f_name))))
     }
     fn emit_tuple<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
         f(self)
     }
-    fn emit_tuple_arg<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_tuple_arg<F>(&mut self, idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Element(idx))))
     }
     fn emit_tuple_struct<F>(&mut self, _: &str, len: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
@@ -262,12 +408,12 @@ impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
         f(self)
     }
     fn emit_option_none(&mut self) -> EncodingResult<()> {
-        self.writer.write_u8(0).map_err(wrap_io)
+        self.write_byte(0)
     }
     fn emit_option_some<F>(&mut self, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        try!(self.writer.write_u8(1).map_err(wrap_io));
+        try!(self.write_byte(1));
         f(self)
     }
     fn emit_seq<F>(&mut self, len: usize, f: F) -> EncodingResult<()>
@@ -276,10 +422,10 @@ impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
         try!(self.emit_usize(len));
         f(self)
     }
-    fn emit_seq_elt<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_seq_elt<F>(&mut self, idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Index(idx))))
     }
     fn emit_map<F>(&mut self, len: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
@@ -287,15 +433,15 @@ impl<'a, W: Write> Encoder for EncoderWriter<'a, W> {
         try!(self.emit_usize(len));
         f(self)
     }
-    fn emit_map_elt_key<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_map_elt_key<F>(&mut self, idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Index(idx))))
     }
-    fn emit_map_elt_val<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_map_elt_val<F>(&mut self, idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut EncoderWriter<'a, W>) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Index(idx))))
     }
 
 }
@@ -319,7 +465,7 @@ impl Encoder for SizeChecker {
         self.add_value_unsigned(v)
     }
     fn emit_u8(&mut self, _: u8) -> EncodingResult<()> {
-        self.add_value_unsigned(0 as u8)
+        self.add_raw(1)
     }
     fn emit_isize(&mut self, v: isize) -> EncodingResult<()> {
         self.add_value_signed(v as i64)
@@ -333,11 +479,12 @@ impl Encoder for SizeChecker {
     fn emit_i16(&mut self, v: i16) -> EncodingResult<()> {
         self.add_value_signed(v)
     }
-    fn emit_i8(&mut self, v: i8) -> EncodingResult<()> {
-        self.add_value_signed(v)
+    fn emit_i8(&mut self, _: i8) -> EncodingResult<()> {
+        // Written as a single byte, not as a varint.
+        self.add_raw(1)
     }
     fn emit_bool(&mut self, _: bool) -> EncodingResult<()> {
-        self.add_value_unsigned(0 as u8)
+        self.add_raw(1)
     }
     fn emit_f64(&mut self, _: f64) -> EncodingResult<()> {
         let bytes = self.float_size_f64;
@@ -362,13 +509,13 @@ impl Encoder for SizeChecker {
     fn emit_enum_variant<F>(&mut self, _: &str, v_id: usize, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        self.add_value_unsigned(v_id as u32)?;
+        self.add_value_unsigned(v_id as u64)?;
         f(self)
     }
-    fn emit_enum_variant_arg<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_enum_variant_arg<F>(&mut self, a_idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Element(a_idx))))
     }
     fn emit_enum_struct_variant<F>(&mut self,
                                    _: &str,
@@ -380,30 +527,30 @@ impl Encoder for SizeChecker {
     {
         f(self)
     }
-    fn emit_enum_struct_variant_field<F>(&mut self, _: &str, _: usize, f: F) -> EncodingResult<()>
+    fn emit_enum_struct_variant_field<F>(&mut self, f_name: &str, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Field(f_name))))
     }
-    fn emit_struct<F>(&mut self, _: &str, _: usize, f: F) -> EncodingResult<()>
+    fn emit_struct<F>(&mut self, name: &str, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Struct(name))))
     }
-    fn emit_struct_field<F>(&mut self, _: &str, _: usize, f: F) -> EncodingResult<()>
+    fn emit_struct_field<F>(&mut self, f_name: &str, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Field(f_name))))
     }
     fn emit_tuple<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
         f(self)
     }
-    fn emit_tuple_arg<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_tuple_arg<F>(&mut self, idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Element(idx))))
     }
     fn emit_tuple_struct<F>(&mut self, _: &str, len: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
@@ -421,12 +568,12 @@ impl Encoder for SizeChecker {
         f(self)
     }
     fn emit_option_none(&mut self) -> EncodingResult<()> {
-        self.add_value_unsigned(0 as u8)
+        self.add_raw(1)
     }
     fn emit_option_some<F>(&mut self, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        try!(self.add_value_unsigned(1 as u8));
+        try!(self.add_raw(1));
         f(self)
     }
     fn emit_seq<F>(&mut self, len: usize, f: F) -> EncodingResult<()>
@@ -435,10 +582,10 @@ impl Encoder for SizeChecker {
         try!(self.emit_usize(len));
         f(self)
     }
-    fn emit_seq_elt<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_seq_elt<F>(&mut self, idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Index(idx))))
     }
     fn emit_map<F>(&mut self, len: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
@@ -446,15 +593,15 @@ impl Encoder for SizeChecker {
         try!(self.emit_usize(len));
         f(self)
     }
-    fn emit_map_elt_key<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_map_elt_key<F>(&mut self, idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Index(idx))))
     }
-    fn emit_map_elt_val<F>(&mut self, _: usize, f: F) -> EncodingResult<()>
+    fn emit_map_elt_val<F>(&mut self, idx: usize, f: F) -> EncodingResult<()>
         where F: FnOnce(&mut SizeChecker) -> EncodingResult<()>
     {
-        f(self)
+        f(self).map_err(|err| err.locate(self.written, Some(Segment::Index(idx))))
     }
 
 }
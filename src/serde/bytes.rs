@@ -0,0 +1,28 @@
+//! The visitor for values that are kept in their encoded form.
+
+use std::fmt;
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use serde_crate as serde;
+
+/// Visits length prefixed bytes and keeps them as they are, for `Lazy` and
+/// `RawValue`.
+pub struct BytesVisitor;
+
+impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("encoded bytes")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+}
@@ -0,0 +1,93 @@
+//! Long-lived encoders and decoders that reuse their buffers.
+
+
+use ::{SizeLimit, Config};
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use serde_crate as serde;
+
+use super::{serialize_bounded, Deserializer, DeserializeResult, SerializeResult};
+
+/// Encodes values into a buffer that is reused for every value.
+///
+/// Once the buffer has grown to the size of the largest value, encoding does
+/// not allocate, except with `StructEncoding::LengthPrefixed` and
+/// `StructEncoding::PresenceBitmap`, which buffer the fields of every struct.
+///
+/// ```rust
+/// use mincode::{Encoder, SizeLimit, FloatEncoding};
+///
+/// let mut encoder = Encoder::new(SizeLimit::Bounded(1200), FloatEncoding::Normal);
+/// for tick in 0..3u32 {
+///     let packet: &[u8] = encoder.encode(&(tick, "position")).unwrap();
+///     assert_eq!(packet.len(), 1 + 1 + 8);
+/// }
+/// ```
+pub struct Encoder {
+    buffer: Vec<u8>,
+    size_limit: SizeLimit,
+    config: Config,
+}
+
+impl Encoder {
+    pub fn new<C: Into<Config>>(size_limit: SizeLimit, config: C) -> Encoder {
+        Encoder {
+            buffer: Vec::new(),
+            size_limit: size_limit,
+            config: config.into(),
+        }
+    }
+
+    /// Encodes a value and returns its bytes, which are valid until the next call.
+    pub fn encode<T: serde::Serialize>(&mut self, value: &T) -> SerializeResult<&[u8]> {
+        let limit = match self.size_limit {
+            SizeLimit::Bounded(x) => x,
+            SizeLimit::Infinite => u64::MAX,
+        };
+        self.buffer.clear();
+        try!(serialize_bounded(&mut self.buffer, value, limit, self.config));
+        Ok(&self.buffer)
+    }
+}
+
+/// Decodes values, reusing the scratch space that strings are read into.
+///
+/// Together with `decode_in_place` and an `Encoder`, sending and receiving a
+/// value of the same size as before does not allocate.
+pub struct Decoder {
+    scratch: Vec<u8>,
+    size_limit: SizeLimit,
+    config: Config,
+}
+
+impl Decoder {
+    pub fn new<C: Into<Config>>(size_limit: SizeLimit, config: C) -> Decoder {
+        Decoder {
+            scratch: Vec::new(),
+            size_limit: size_limit,
+            config: config.into(),
+        }
+    }
+
+    /// Decodes a value from a slice of bytes.
+    pub fn decode<T: serde::de::DeserializeOwned>(&mut self, bytes: &[u8]) -> DeserializeResult<T> {
+        let mut reader = bytes;
+        let mut deserializer = Deserializer::new(&mut reader, self.size_limit, self.config);
+        deserializer.swap_buffer(&mut self.scratch);
+        let result = serde::Deserialize::deserialize(&mut deserializer);
+        deserializer.swap_buffer(&mut self.scratch);
+        result.map_err(|err| err.locate(deserializer.bytes_read(), None))
+    }
+
+    /// Decodes a slice of bytes into an existing value, reusing its allocations
+    /// like `deserialize_in_place`.
+    pub fn decode_in_place<T: serde::de::DeserializeOwned>(&mut self, place: &mut T, bytes: &[u8]) -> DeserializeResult<()> {
+        let mut reader = bytes;
+        let mut deserializer = Deserializer::new(&mut reader, self.size_limit, self.config);
+        deserializer.swap_buffer(&mut self.scratch);
+        let result = serde::Deserialize::deserialize_in_place(&mut deserializer, place);
+        deserializer.swap_buffer(&mut self.scratch);
+        result.map_err(|err| err.locate(deserializer.bytes_read(), None))
+    }
+}
@@ -0,0 +1,141 @@
+//! Encoding iterators as sequences without collecting them.
+
+use std::marker::PhantomData;
+
+use ::{SizeLimit, Config};
+use error::Segment;
+use io::{Reader, Writer};
+
+use serde_crate as serde;
+use serde_crate::ser::{Error, SerializeSeq};
+
+use super::{Deserializer, DeserializeResult, Serializer, SerializeResult};
+
+/// Serializes the elements of an iterator like a `Vec` of them.
+///
+/// The length is taken from `ExactSizeIterator::len`, and the iterator is
+/// cloned every time the sequence is serialized, so that it also works with
+/// `serialized_size`.
+///
+/// ```rust
+/// use mincode::{SeqIter, FloatEncoding, SizeLimit};
+/// use mincode::serde::serialize;
+///
+/// let entities = vec![1u32, 2, 3, 4];
+/// let view = SeqIter(entities.iter().skip(1));
+/// let bytes = serialize(&view, SizeLimit::Infinite, FloatEncoding::Normal).unwrap();
+/// assert_eq!(bytes, vec![3, 2, 3, 4]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SeqIter<I>(pub I);
+
+impl<I> serde::Serialize for SeqIter<I>
+    where I: ExactSizeIterator + Clone,
+          I::Item: serde::Serialize,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_exact(serializer, self.0.clone())
+    }
+}
+
+/// Serializes an iterator as a sequence whose length is `iter.len()`.
+///
+/// An iterator that yields a different number of elements is an error, as
+/// the length prefix would not match the elements.
+fn serialize_exact<S, I>(serializer: S, iter: I) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer,
+          I: ExactSizeIterator,
+          I::Item: serde::Serialize,
+{
+    let len = iter.len();
+    let mut seq = try!(serializer.serialize_seq(Some(len)));
+    let mut count = 0;
+    for item in iter {
+        if count == len {
+            return Err(S::Error::custom("the iterator yielded more elements than its length"));
+        }
+        try!(seq.serialize_element(&item));
+        count += 1;
+    }
+    if count < len {
+        return Err(S::Error::custom("the iterator yielded fewer elements than its length"));
+    }
+    seq.end()
+}
+
+/// Serializes the elements of an iterator directly into a `Writer`, like a
+/// `Vec` of them.
+///
+/// There is no size limit, as the elements are written as they are produced.
+/// If this returns a `SerializeError`, assume that the writer is in an
+/// invalid state.
+pub fn serialize_iter<W, I, C>(writer: &mut W, iter: I, config: C) -> SerializeResult<()>
+    where W: Writer,
+          I: IntoIterator,
+          I::IntoIter: ExactSizeIterator,
+          I::Item: serde::Serialize,
+          C: Into<Config>,
+{
+    let mut serializer = Serializer::new(writer, config);
+    let result = serialize_exact(&mut serializer, iter.into_iter());
+    result.map_err(|err| err.locate(serializer.bytes_written(), None))
+}
+
+/// Reads the length of a sequence from a `Reader`, and returns an iterator
+/// that decodes its elements one at a time.
+///
+/// The size limit applies to the whole sequence. After an element fails to
+/// decode the iterator ends, as the reader is left in an invalid state.
+pub fn deserialize_iter<'a, R, T, C>(reader: &'a mut R, size_limit: SizeLimit, config: C) -> DeserializeResult<DeserializeIter<'a, R, T>>
+    where R: Reader,
+          T: serde::de::DeserializeOwned,
+          C: Into<Config>,
+{
+    let mut deserializer = Deserializer::new(reader, size_limit, config);
+    let len = try!(serde::Deserialize::deserialize(&mut deserializer).map_err(|err|
+        err.locate(deserializer.bytes_read(), None)));
+    Ok(DeserializeIter {
+        deserializer: deserializer,
+        len: len,
+        index: 0,
+        marker: PhantomData,
+    })
+}
+
+/// An iterator over the elements of a sequence in a `Reader`, see `deserialize_iter`.
+pub struct DeserializeIter<'a, R: 'a, T> {
+    deserializer: Deserializer<'a, R>,
+    len: usize,
+    index: usize,
+    marker: PhantomData<T>,
+}
+
+impl<'a, R: Reader, T> DeserializeIter<'a, R, T> {
+    /// Returns the number of bytes read from the contained Reader.
+    pub fn bytes_read(&self) -> u64 {
+        self.deserializer.bytes_read()
+    }
+}
+
+impl<'a, R: Reader, T: serde::de::DeserializeOwned> Iterator for DeserializeIter<'a, R, T> {
+    type Item = DeserializeResult<T>;
+
+    fn next(&mut self) -> Option<DeserializeResult<T>> {
+        if self.index == self.len {
+            return None;
+        }
+        let index = self.index;
+        self.index += 1;
+        let result = serde::Deserialize::deserialize(&mut self.deserializer);
+        if result.is_err() {
+            self.index = self.len;
+        }
+        Some(result.map_err(|err| err.locate(self.deserializer.bytes_read(), Some(Segment::Index(index)))))
+    }
+
+    /// The length comes from the input, which may be lying, and an element
+    /// that fails to decode ends the iteration, so it is only an upper bound.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.len - self.index))
+    }
+}
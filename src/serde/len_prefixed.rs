@@ -0,0 +1,90 @@
+//! Encodes a fixed-size array with its length in front, for
+//! `#[serde(with = "mincode::serde::len_prefixed")]`.
+//!
+//! serde serializes arrays exactly like tuples, so the `serde` backend
+//! encodes them without a length. Older versions of it, and the
+//! `rustc_serialize` backend, write the length in front of an array like in
+//! front of a slice. Fields with this attribute are encoded that way, so
+//! that data written like that can still be read. `Config::array_len_prefix`
+//! does the same for all arrays, but also for all other tuples.
+//!
+//! Reading works for the arrays that implement `Default`, those of up to 32
+//! elements that implement `Default` themselves.
+//!
+//! ```rust
+//! # extern crate mincode;
+//! # #[macro_use] extern crate serde_derive;
+//! use mincode::FloatEncoding;
+//! use mincode::serde::deserialize;
+//!
+//! #[derive(Deserialize, PartialEq, Debug)]
+//! struct Entity {
+//!     id: u8,
+//!     #[serde(with = "mincode::serde::len_prefixed")]
+//!     position: [u8; 3],
+//! }
+//!
+//! # fn main() {
+//! let old = [1, 3, 4, 5, 6];
+//! let entity: Entity = deserialize(&old, FloatEncoding::Normal).unwrap();
+//! assert_eq!(entity, Entity { id: 1, position: [4, 5, 6] });
+//! # }
+//! ```
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde_crate as serde;
+
+/// Serializes `array` with its length in front.
+pub fn serialize<A, T, S>(array: &A, serializer: S) -> Result<S::Ok, S::Error>
+    where A: AsRef<[T]>, T: serde::Serialize, S: serde::Serializer,
+{
+    use serde_crate::ser::SerializeSeq;
+
+    let array = array.as_ref();
+    let mut seq = serializer.serialize_seq(Some(array.len()))?;
+    for element in array {
+        seq.serialize_element(element)?;
+    }
+    seq.end()
+}
+
+/// Deserializes an array with its length in front, which must be the
+/// length of `A`.
+pub fn deserialize<'de, A, T, D>(deserializer: D) -> Result<A, D::Error>
+    where A: Default + AsMut<[T]>, T: serde::Deserialize<'de>, D: serde::Deserializer<'de>,
+{
+    struct ArrayVisitor<A, T> {
+        marker: PhantomData<(A, T)>,
+    }
+
+    impl<'de, A, T> serde::de::Visitor<'de> for ArrayVisitor<A, T>
+        where A: Default + AsMut<[T]>, T: serde::Deserialize<'de>,
+    {
+        type Value = A;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an array with its length in front")
+        }
+
+        fn visit_seq<S>(self, mut seq: S) -> Result<A, S::Error>
+            where S: serde::de::SeqAccess<'de>,
+        {
+            let mut array = A::default();
+            let len = array.as_mut().len();
+            for (i, element) in array.as_mut().iter_mut().enumerate() {
+                match seq.next_element()? {
+                    Some(value) => *element = value,
+                    None => return Err(serde::de::Error::invalid_length(i, &self)),
+                }
+            }
+            if seq.next_element::<serde::de::IgnoredAny>()?.is_some() {
+                return Err(serde::de::Error::invalid_length(len + 1, &self));
+            }
+            Ok(array)
+        }
+    }
+
+    deserializer.deserialize_seq(ArrayVisitor { marker: PhantomData })
+}
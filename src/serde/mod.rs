@@ -2,8 +2,10 @@
 //! that use the `serde` crate for the serializable and deserializable
 //! implementation.
 
-use std::io::{Write, Read};
-use ::{SizeLimit, FloatEncoding};
+use ::{SizeLimit, Config, DecodeLimits};
+use io::{Reader, Writer};
+#[cfg(not(feature = "std"))]
+use prelude::*;
 
 pub use self::reader::{
     Deserializer,
@@ -16,14 +18,24 @@ pub use self::writer::{
     Serializer,
     SerializeResult,
     SerializeError,
+    SizeChecker,
 };
 
-use self::writer::SizeChecker;
+pub use self::codec::{Encoder, Decoder};
+pub use self::iter::{SeqIter, DeserializeIter, serialize_iter, deserialize_iter};
+pub use self::stream::StreamDeserializer;
+pub(crate) use self::bytes::BytesVisitor;
 
 use serde_crate as serde;
 
 mod reader;
 mod writer;
+mod codec;
+mod iter;
+mod stream;
+mod presence;
+mod bytes;
+pub mod len_prefixed;
 
 /// Serializes an object directly into a `Writer`.
 ///
@@ -33,53 +45,78 @@ mod writer;
 /// If this returns an `SerializeError` (other than SizeLimit), assume that the
 /// writer is in an invalid state, as writing could bail out in the middle of
 /// serializing.
-pub fn serialize_into<W, T>(writer: &mut W, value: &T, size_limit: SizeLimit, float_enc: FloatEncoding) -> SerializeResult<()>
-    where W: Write, T: serde::Serialize,
+pub fn serialize_into<W, T, C>(writer: &mut W, value: &T, size_limit: SizeLimit, config: C) -> SerializeResult<()>
+    where W: Writer, T: serde::Serialize, C: Into<Config>,
 {
     match size_limit {
-        SizeLimit::Infinite => { }
-        SizeLimit::Bounded(x) => {
-            let mut size_checker = SizeChecker::new(x, float_enc);
-            try!(value.serialize(&mut size_checker))
+        SizeLimit::Infinite => serialize_bounded(writer, value, u64::MAX, config),
+        SizeLimit::Bounded(_) => {
+            // The value is buffered, so that nothing is written if it is too big.
+            let buffer = try!(serialize(value, size_limit, config));
+            writer.write_all(&buffer).map_err(SerializeError::IoError)
         }
     }
+}
 
-    let mut serializer = Serializer::new(writer, float_enc);
-    serde::Serialize::serialize(value, &mut serializer)
+/// Serializes an object into a slice and returns the number of bytes written.
+///
+/// If the object does not fit, a `BufferTooSmall` error with the serialized size of
+/// the object is returned and the contents of the slice are unspecified.
+pub fn serialize_into_slice<T, C>(buffer: &mut [u8], value: &T, config: C) -> SerializeResult<usize>
+    where T: serde::Serialize, C: Into<Config>,
+{
+    let config = config.into();
+    let limit = buffer.len() as u64;
+    let mut writer = buffer;
+    let mut serializer = Serializer::bounded(&mut writer, limit, config);
+    match serde::Serialize::serialize(value, &mut serializer) {
+        Ok(()) => Ok(serializer.bytes_written() as usize),
+        Err(err) => {
+            let err = err.locate(serializer.bytes_written(), None);
+            if let SerializeError::At(ref inner, _) = err {
+                if let SerializeError::SizeLimit = **inner {
+                    return Err(SerializeError::BufferTooSmall {
+                        required: serialized_size(value, config),
+                    });
+                }
+            }
+            Err(err)
+        }
+    }
 }
 
 /// Serializes a serializable object into a `Vec` of bytes.
 ///
 /// If the serialization would take more bytes than allowed by `size_limit`,
 /// an error is returned.
-pub fn serialize<T>(value: &T, size_limit: SizeLimit, float_enc: FloatEncoding) -> SerializeResult<Vec<u8>>
-    where T: serde::Serialize,
+pub fn serialize<T, C>(value: &T, size_limit: SizeLimit, config: C) -> SerializeResult<Vec<u8>>
+    where T: serde::Serialize, C: Into<Config>,
 {
-    // Since we are putting values directly into a vector, we can do size
-    // computation out here and pre-allocate a buffer of *exactly*
-    // the right size.
-    let mut writer = match size_limit {
-        SizeLimit::Bounded(size_limit) => {
-            let actual_size = match serialized_size_bounded(value, size_limit, float_enc) {
-                Some(actual_size) => actual_size,
-                None => { return Err(SerializeError::SizeLimit); }
-            };
-            Vec::with_capacity(actual_size as usize)
-        }
-        SizeLimit::Infinite => Vec::new()
+    let limit = match size_limit {
+        SizeLimit::Bounded(x) => x,
+        SizeLimit::Infinite => u64::MAX,
     };
-
-    try!(serialize_into(&mut writer, value, SizeLimit::Infinite, float_enc));
+    // The size limit is checked while writing, a value that is too big
+    // is only serialized up to the limit.
+    let mut writer = Vec::new();
+    try!(serialize_bounded(&mut writer, value, limit, config));
     Ok(writer)
 }
 
+fn serialize_bounded<W, T, C>(writer: &mut W, value: &T, limit: u64, config: C) -> SerializeResult<()>
+    where W: Writer, T: serde::Serialize, C: Into<Config>,
+{
+    let mut serializer = Serializer::bounded(writer, limit, config);
+    serde::Serialize::serialize(value, &mut serializer).map_err(|err|
+        err.locate(serializer.bytes_written(), None))
+}
+
 /// Returns the size that an object would be if serialized using bincode.
 ///
 /// This is used internally as part of the check for encode_into, but it can
 /// be useful for preallocating buffers if thats your style.
-pub fn serialized_size<T: serde::Serialize>(value: &T, float_enc: FloatEncoding) -> u64 {
-    use std::u64::MAX;
-    let mut size_checker = SizeChecker::new(MAX, float_enc);
+pub fn serialized_size<T: serde::Serialize, C: Into<Config>>(value: &T, config: C) -> u64 {
+    let mut size_checker = SizeChecker::new(u64::MAX, config.into());
     value.serialize(&mut size_checker).ok();
     size_checker.written
 }
@@ -89,8 +126,8 @@ pub fn serialized_size<T: serde::Serialize>(value: &T, float_enc: FloatEncoding)
 ///
 /// If it can be serialized in `max` or fewer bytes, that number will be returned
 /// inside `Some`.  If it goes over bounds, then None is returned.
-pub fn serialized_size_bounded<T: serde::Serialize>(value: &T, max: u64, float_enc: FloatEncoding) -> Option<u64> {
-    let mut size_checker = SizeChecker::new(max, float_enc);
+pub fn serialized_size_bounded<T: serde::Serialize, C: Into<Config>>(value: &T, max: u64, config: C) -> Option<u64> {
+    let mut size_checker = SizeChecker::new(max, config.into());
     value.serialize(&mut size_checker).ok().map(|_| size_checker.written)
 }
 
@@ -103,21 +140,118 @@ pub fn serialized_size_bounded<T: serde::Serialize>(value: &T, max: u64, float_e
 /// If this returns an `DeserializeError`, assume that the buffer that you passed
 /// in is in an invalid state, as the error could be returned during any point
 /// in the reading.
-pub fn deserialize_from<R, T>(reader: &mut R, size_limit: SizeLimit, float_enc: FloatEncoding) -> DeserializeResult<T>
-    where R: Read,
-          T: serde::Deserialize,
+pub fn deserialize_from<R, T, C>(reader: &mut R, size_limit: SizeLimit, config: C) -> DeserializeResult<T>
+    where R: Reader,
+          T: serde::de::DeserializeOwned,
+          C: Into<Config>,
 {
-    let mut deserializer = Deserializer::new(reader, size_limit, float_enc);
-    serde::Deserialize::deserialize(&mut deserializer)
+    let mut deserializer = Deserializer::new(reader, size_limit, config);
+    serde::Deserialize::deserialize(&mut deserializer).map_err(|err|
+        err.locate(deserializer.bytes_read(), None))
 }
 
 /// Deserializes a slice of bytes into an object.
 ///
-/// This method does not have a size-limit because if you already have the bytes
-/// in memory, then you don't gain anything by having a limiter.
-pub fn deserialize<T>(bytes: &[u8], float_enc: FloatEncoding) -> DeserializeResult<T>
-    where T: serde::Deserialize,
+/// The length of the slice is the size limit, so a bogus length in the input
+/// never makes a collection preallocate for more elements than there are bytes
+/// left, and reading past the end of the slice fails with `SizeLimit`.
+pub fn deserialize<T, C>(bytes: &[u8], config: C) -> DeserializeResult<T>
+    where T: serde::de::DeserializeOwned,
+          C: Into<Config>,
+{
+    let mut reader = bytes;
+    deserialize_from(&mut reader, SizeLimit::Bounded(bytes.len() as u64), config)
+}
+
+/// Deserializes an object from the start of a slice of bytes, and returns it
+/// together with the number of bytes it was decoded from.
+///
+/// The length of the slice is the size limit, like in `deserialize`.
+pub fn deserialize_with_len<T, C>(bytes: &[u8], config: C) -> DeserializeResult<(T, usize)>
+    where T: serde::de::DeserializeOwned,
+          C: Into<Config>,
+{
+    let mut reader = bytes;
+    let size_limit = SizeLimit::Bounded(bytes.len() as u64);
+    let mut deserializer = Deserializer::new(&mut reader, size_limit, config);
+    let value = try!(serde::Deserialize::deserialize(&mut deserializer).map_err(|err|
+        err.locate(deserializer.bytes_read(), None)));
+    Ok((value, deserializer.bytes_read() as usize))
+}
+
+/// Deserializes a slice of bytes into an object, failing with `InvalidEncoding`
+/// if any bytes are left after it.
+pub fn deserialize_exact<T, C>(bytes: &[u8], config: C) -> DeserializeResult<T>
+    where T: serde::de::DeserializeOwned,
+          C: Into<Config>,
+{
+    let (value, len) = try!(deserialize_with_len(bytes, config));
+    if len == bytes.len() {
+        Ok(value)
+    } else {
+        let err = DeserializeError::InvalidEncoding(InvalidEncoding {
+            desc: "trailing bytes after the value",
+            detail: Some(format!("Expected {} bytes, got {}", len, bytes.len()))
+        });
+        Err(err.locate(len as u64, None))
+    }
+}
+
+/// Deserializes an object from a `Reader` that may be untrusted.
+///
+/// Like `deserialize_from`, but lengths in the input that claim more than
+/// `limits` allow fail with `SizeLimit` before anything is allocated for them.
+pub fn deserialize_from_with_limits<R, T, C>(reader: &mut R, size_limit: SizeLimit, limits: DecodeLimits, config: C) -> DeserializeResult<T>
+    where R: Reader,
+          T: serde::de::DeserializeOwned,
+          C: Into<Config>,
+{
+    let mut deserializer = Deserializer::with_limits(reader, size_limit, limits, config);
+    serde::Deserialize::deserialize(&mut deserializer).map_err(|err|
+        err.locate(deserializer.bytes_read(), None))
+}
+
+/// Deserializes a slice of bytes that may be untrusted into an object.
+///
+/// The length of the slice is the size limit, so collections are never
+/// preallocated for more elements than there are bytes left; reading past
+/// the end of the slice fails with `SizeLimit`.
+pub fn deserialize_with_limits<T, C>(bytes: &[u8], limits: DecodeLimits, config: C) -> DeserializeResult<T>
+    where T: serde::de::DeserializeOwned,
+          C: Into<Config>,
+{
+    let mut reader = bytes;
+    let size_limit = SizeLimit::Bounded(bytes.len() as u64);
+    deserialize_from_with_limits(&mut reader, size_limit, limits, config)
+}
+
+/// Deserializes an object directly from a `Reader` into an existing value,
+/// reusing its allocations.
+///
+/// Vectors keep their capacity and strings their buffers where the new
+/// contents fit. Derived types only do this if `serde_derive` is built with
+/// its `deserialize_in_place` feature, otherwise they are replaced.
+///
+/// Size limits are handled like in `deserialize_from`. If this returns a
+/// `DeserializeError`, `place` may have been partially overwritten.
+pub fn deserialize_from_in_place<R, T, C>(place: &mut T, reader: &mut R, size_limit: SizeLimit, config: C) -> DeserializeResult<()>
+    where R: Reader,
+          T: serde::de::DeserializeOwned,
+          C: Into<Config>,
+{
+    let mut deserializer = Deserializer::new(reader, size_limit, config);
+    serde::Deserialize::deserialize_in_place(&mut deserializer, place).map_err(|err|
+        err.locate(deserializer.bytes_read(), None))
+}
+
+/// Deserializes a slice of bytes into an existing value, reusing its allocations.
+///
+/// See `deserialize_from_in_place` for which allocations are reused. The length
+/// of the slice is the size limit, like in `deserialize`.
+pub fn deserialize_in_place<T, C>(place: &mut T, bytes: &[u8], config: C) -> DeserializeResult<()>
+    where T: serde::de::DeserializeOwned,
+          C: Into<Config>,
 {
     let mut reader = bytes;
-    deserialize_from(&mut reader, SizeLimit::Infinite, float_enc)
+    deserialize_from_in_place(place, &mut reader, SizeLimit::Bounded(bytes.len() as u64), config)
 }
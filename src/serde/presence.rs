@@ -0,0 +1,232 @@
+//! The presence bitmap of `StructEncoding::PresenceBitmap`.
+//!
+//! The fields of such a struct are probed while they are encoded: a field
+//! that serializes as an `Option` adds a bit to the bitmap instead of its tag
+//! byte, any other field is encoded as usual. Decoding probes the fields the
+//! same way, so both sides agree on which bit belongs to which field.
+
+use serde_crate as serde;
+
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use super::reader::{Deserializer, DeserializeError, DeserializeResult};
+use super::writer::SerializeError;
+use io::Reader;
+
+/// The presence bits of the `Option` fields of a struct, in field order.
+///
+/// It is encoded like a LEB128 integer of any length: seven bits to a byte,
+/// the first field in the lowest bit, and the high bit set on all bytes but
+/// the last. Trailing absent fields are left out, so a struct with all of
+/// them absent takes one byte.
+#[derive(Default)]
+pub struct Bitmap {
+    /// Seven bits to a byte, without the continuation bits.
+    bytes: Vec<u8>,
+    /// The number of bits that have been added or taken.
+    bits: usize,
+}
+
+impl Bitmap {
+    /// Wraps the bytes of an encoded bitmap.
+    pub fn from_encoded(mut bytes: Vec<u8>) -> Bitmap {
+        for byte in &mut bytes {
+            *byte &= 0x7f;
+        }
+        Bitmap { bytes: bytes, bits: 0 }
+    }
+
+    fn push(&mut self, present: bool) {
+        if self.bits.is_multiple_of(7) {
+            self.bytes.push(0);
+        }
+        if present {
+            *self.bytes.last_mut().unwrap() |= 1 << (self.bits % 7);
+        }
+        self.bits += 1;
+    }
+
+    /// Takes the next bit, bits past the end of the bitmap are absent.
+    fn next(&mut self) -> bool {
+        let byte = self.bytes.get(self.bits / 7).cloned().unwrap_or(0);
+        let present = byte & (1 << (self.bits % 7)) != 0;
+        self.bits += 1;
+        present
+    }
+
+    /// Returns whether bits are set that have not been taken.
+    pub fn has_remaining(&self) -> bool {
+        (self.bits..self.bytes.len() * 7).any(|bit| self.bytes[bit / 7] & (1 << (bit % 7)) != 0)
+    }
+
+    /// The number of bytes the bitmap is encoded to.
+    pub fn encoded_len(&self) -> usize {
+        let mut len = self.bytes.len();
+        while len > 1 && self.bytes[len - 1] == 0 {
+            len -= 1;
+        }
+        if len == 0 { 1 } else { len }
+    }
+
+    /// Encodes the bitmap.
+    pub fn encode(&self) -> Vec<u8> {
+        let len = self.encoded_len();
+        (0..len).map(|i| {
+            let byte = self.bytes.get(i).cloned().unwrap_or(0);
+            if i + 1 < len { byte | 0x80 } else { byte }
+        }).collect()
+    }
+}
+
+/// A Serializer for a field of a struct, that adds the presence of an
+/// `Option` to the bitmap and passes everything else on to `ser`.
+pub struct OptionProbe<'p, S> {
+    pub ser: S,
+    pub bitmap: &'p mut Bitmap,
+}
+
+macro_rules! forward_serialize {
+    ($($method:ident($($arg:ident: $ty:ty),*) -> $result:ty;)*) => {
+        $(
+            fn $method(self, $($arg: $ty),*) -> Result<$result, SerializeError> {
+                serde::Serializer::$method(self.ser, $($arg),*)
+            }
+        )*
+    };
+}
+
+impl<'p, S> serde::Serializer for OptionProbe<'p, S>
+    where S: serde::Serializer<Ok = (), Error = SerializeError>,
+{
+    type Ok = ();
+    type Error = SerializeError;
+    type SerializeSeq = S::SerializeSeq;
+    type SerializeTuple = S::SerializeTuple;
+    type SerializeTupleStruct = S::SerializeTupleStruct;
+    type SerializeTupleVariant = S::SerializeTupleVariant;
+    type SerializeMap = S::SerializeMap;
+    type SerializeStruct = S::SerializeStruct;
+    type SerializeStructVariant = S::SerializeStructVariant;
+
+    fn is_human_readable(&self) -> bool { false }
+
+    fn serialize_none(self) -> Result<(), SerializeError> {
+        self.bitmap.push(false);
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + serde::Serialize>(self, value: &T) -> Result<(), SerializeError> {
+        self.bitmap.push(true);
+        value.serialize(self.ser)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + serde::Serialize>(self, name: &'static str, value: &T) -> Result<(), SerializeError> {
+        serde::Serializer::serialize_newtype_struct(self.ser, name, value)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + serde::Serialize>(self,
+                                                               name: &'static str,
+                                                               variant_index: u32,
+                                                               variant: &'static str,
+                                                               value: &T) -> Result<(), SerializeError> {
+        serde::Serializer::serialize_newtype_variant(self.ser, name, variant_index, variant, value)
+    }
+
+    forward_serialize! {
+        serialize_bool(v: bool) -> ();
+        serialize_i8(v: i8) -> ();
+        serialize_i16(v: i16) -> ();
+        serialize_i32(v: i32) -> ();
+        serialize_i64(v: i64) -> ();
+        serialize_i128(v: i128) -> ();
+        serialize_u8(v: u8) -> ();
+        serialize_u16(v: u16) -> ();
+        serialize_u32(v: u32) -> ();
+        serialize_u64(v: u64) -> ();
+        serialize_u128(v: u128) -> ();
+        serialize_f32(v: f32) -> ();
+        serialize_f64(v: f64) -> ();
+        serialize_char(v: char) -> ();
+        serialize_str(v: &str) -> ();
+        serialize_bytes(v: &[u8]) -> ();
+        serialize_unit() -> ();
+        serialize_unit_struct(name: &'static str) -> ();
+        serialize_unit_variant(name: &'static str, variant_index: u32, variant: &'static str) -> ();
+        serialize_seq(len: Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(len: usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(name: &'static str, len: usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(name: &'static str, variant_index: u32, variant: &'static str, len: usize) -> Self::SerializeTupleVariant;
+        serialize_map(len: Option<usize>) -> Self::SerializeMap;
+        serialize_struct(name: &'static str, len: usize) -> Self::SerializeStruct;
+        serialize_struct_variant(name: &'static str, variant_index: u32, variant: &'static str, len: usize) -> Self::SerializeStructVariant;
+    }
+}
+
+/// A Deserializer for a field of a struct, that takes the presence of an
+/// `Option` from the bitmap and passes everything else on to `de`.
+pub struct PresenceProbe<'p, 'a: 'p, R: 'a> {
+    pub de: &'p mut Deserializer<'a, R>,
+    pub bitmap: &'p mut Bitmap,
+}
+
+macro_rules! forward_deserialize {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method<V>(self, $($arg: $ty,)* visitor: V) -> DeserializeResult<V::Value>
+                where V: serde::de::Visitor<'de>,
+            {
+                serde::Deserializer::$method(self.de, $($arg,)* visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'p, 'a, R: Reader> serde::Deserializer<'de> for PresenceProbe<'p, 'a, R> {
+    type Error = DeserializeError;
+
+    fn is_human_readable(&self) -> bool { false }
+
+    fn deserialize_option<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
+    {
+        if self.bitmap.next() {
+            self.de.nested(|de| visitor.visit_some(de))
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    forward_deserialize! {
+        deserialize_any();
+        deserialize_bool();
+        deserialize_i8();
+        deserialize_i16();
+        deserialize_i32();
+        deserialize_i64();
+        deserialize_i128();
+        deserialize_u8();
+        deserialize_u16();
+        deserialize_u32();
+        deserialize_u64();
+        deserialize_u128();
+        deserialize_f32();
+        deserialize_f64();
+        deserialize_char();
+        deserialize_str();
+        deserialize_string();
+        deserialize_bytes();
+        deserialize_byte_buf();
+        deserialize_unit();
+        deserialize_unit_struct(name: &'static str);
+        deserialize_newtype_struct(name: &'static str);
+        deserialize_seq();
+        deserialize_tuple(len: usize);
+        deserialize_tuple_struct(name: &'static str, len: usize);
+        deserialize_map();
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+        deserialize_identifier();
+        deserialize_ignored_any();
+    }
+}
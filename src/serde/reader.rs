@@ -1,21 +1,24 @@
-use std::io::Read;
-use std::io::Error as IoError;
-use std::error::Error;
+use std::cmp;
+use std::convert::{From, TryFrom};
 use std::fmt;
-use std::convert::From;
+use std::mem;
+use std::str;
 
-use byteorder::ReadBytesExt;
-use num_traits;
 use serde_crate as serde;
-use serde_crate::de::value::ValueDeserializer;
+use serde_crate::de::IntoDeserializer;
 
-use ::SizeLimit;
+use ::{SizeLimit, Config, DecodeLimits, StructEncoding, ErrorPosition};
+use error::{ErrorKind, Segment, StdError as Error};
+use io::{self, IoError, Reader};
+#[cfg(not(feature = "std"))]
+use prelude::*;
 
-use leb128;
-use conv::*;
+use varint;
 
 use float::*;
 
+use super::presence::{Bitmap, PresenceProbe};
+
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct InvalidEncoding {
     pub desc: &'static str,
@@ -47,10 +50,18 @@ pub enum DeserializeError {
     /// if a stream is corrupted.  A stream produced from `encode` or `encode_into`
     /// should **never** produce an InvalidEncoding error.
     InvalidEncoding(InvalidEncoding),
+    /// If the bytes decode to a value but are not its canonical encoding,
+    /// and `Config::canonical` is set, this error will be returned.
+    NonCanonical(InvalidEncoding),
     /// If decoding a message takes more than the provided size limit, this
     /// error is returned.
     SizeLimit,
-    Serde(serde::de::value::Error)
+    /// If the decoded value is nested deeper than `DecodeLimits::max_depth`,
+    /// this error is returned.
+    DepthLimit,
+    Serde(serde::de::value::Error),
+    /// Another error, together with where in the input it occurred.
+    At(Box<DeserializeError>, ErrorPosition),
 }
 
 pub type DeserializeResult<T> = Result<T, DeserializeError>;
@@ -62,10 +73,16 @@ impl fmt::Display for DeserializeError {
                 write!(fmt, "IoError: {}", ioerr),
             DeserializeError::InvalidEncoding(ref ib) =>
                 write!(fmt, "InvalidEncoding: {}", ib),
+            DeserializeError::NonCanonical(ref ib) =>
+                write!(fmt, "NonCanonical: {}", ib),
             DeserializeError::SizeLimit =>
                 write!(fmt, "SizeLimit"),
+            DeserializeError::DepthLimit =>
+                write!(fmt, "DepthLimit"),
             DeserializeError::Serde(ref s) =>
                 s.fmt(fmt),
+            DeserializeError::At(ref err, ref position) =>
+                write!(fmt, "{} {}", err, position),
         }
     }
 }
@@ -75,9 +92,15 @@ impl Error for DeserializeError {
         match *self {
             DeserializeError::IoError(ref err) => Error::description(err),
             DeserializeError::InvalidEncoding(ref ib) => ib.desc,
+            DeserializeError::NonCanonical(ref ib) => ib.desc,
             DeserializeError::SizeLimit => "the size limit for decoding has been reached",
+            DeserializeError::DepthLimit => "the depth limit for decoding has been reached",
+            #[cfg(feature = "std")]
             DeserializeError::Serde(ref s) => s.description(),
-
+            // Without `std` serde's errors do not implement the error trait.
+            #[cfg(not(feature = "std"))]
+            DeserializeError::Serde(_) => "a custom deserialization error was reported",
+            DeserializeError::At(ref err, _) => err.description(),
         }
     }
 
@@ -85,12 +108,49 @@ impl Error for DeserializeError {
         match *self {
             DeserializeError::IoError(ref err) => err.cause(),
             DeserializeError::InvalidEncoding(_) => None,
+            DeserializeError::NonCanonical(_) => None,
             DeserializeError::SizeLimit => None,
+            DeserializeError::DepthLimit => None,
+            #[cfg(feature = "std")]
             DeserializeError::Serde(ref s) => s.cause(),
+            #[cfg(not(feature = "std"))]
+            DeserializeError::Serde(_) => None,
+            DeserializeError::At(ref err, _) => err.cause(),
         }
     }
 }
 
+impl DeserializeError {
+    /// Returns where in the input the error occurred.
+    pub fn position(&self) -> Option<&ErrorPosition> {
+        match *self {
+            DeserializeError::At(_, ref position) => Some(position),
+            _ => None,
+        }
+    }
+
+    /// Returns the error without its position.
+    pub fn into_inner(self) -> DeserializeError {
+        match self {
+            DeserializeError::At(err, _) => *err,
+            err => err,
+        }
+    }
+
+    /// Attaches the offset if the error has no position yet, and prepends
+    /// the segment to its path.
+    pub(crate) fn locate(self, offset: u64, segment: Option<Segment>) -> DeserializeError {
+        let (err, mut position) = match self {
+            DeserializeError::At(err, position) => (err, position),
+            err => (Box::new(err), ErrorPosition::new(offset)),
+        };
+        if let Some(segment) = segment {
+            position.push_front(segment);
+        }
+        DeserializeError::At(err, position)
+    }
+}
+
 impl From<IoError> for DeserializeError {
     fn from(err: IoError) -> DeserializeError {
         DeserializeError::IoError(err)
@@ -103,13 +163,29 @@ impl From<serde::de::value::Error> for DeserializeError {
     }
 }
 
-impl serde::de::Error for DeserializeError {
-    fn custom<T: Into<String>>(desc: T) -> DeserializeError {
-        DeserializeError::Serde(serde::de::value::Error::Custom(desc.into()))
+impl From<DeserializeError> for ::Error {
+    fn from(err: DeserializeError) -> ::Error {
+        let kind = match err {
+            DeserializeError::IoError(err) => ErrorKind::Io(err),
+            DeserializeError::InvalidEncoding(InvalidEncoding { desc, detail }) =>
+                ErrorKind::InvalidEncoding { desc: desc, detail: detail },
+            DeserializeError::NonCanonical(InvalidEncoding { desc, detail }) =>
+                ErrorKind::NonCanonical { desc: desc, detail: detail },
+            DeserializeError::SizeLimit => ErrorKind::SizeLimit,
+            DeserializeError::DepthLimit => ErrorKind::DepthLimit,
+            DeserializeError::Serde(err) => ErrorKind::Custom(err.to_string()),
+            DeserializeError::At(err, position) => {
+                let err = ::Error::from(*err);
+                return ::Error::new(err.into_kind(), Some(position));
+            }
+        };
+        ::Error::from(kind)
     }
+}
 
-    fn end_of_stream() -> DeserializeError {
-        DeserializeError::Serde(serde::de::value::Error::EndOfStream)
+impl serde::de::Error for DeserializeError {
+    fn custom<T: fmt::Display>(desc: T) -> DeserializeError {
+        DeserializeError::Serde(serde::de::Error::custom(desc))
     }
 }
 
@@ -124,38 +200,72 @@ impl serde::de::Error for DeserializeError {
 /// let bytes_read = d.bytes_read();
 /// ```
 pub struct Deserializer<'a, R: 'a> {
-    reader: &'a mut R,
+    reader: Recorder<'a, R>,
     size_limit: SizeLimit,
     read: u64,
+    struct_enc: StructEncoding,
+    canonical: bool,
+    array_len_prefix: bool,
     read_f32: FloatDecoder<f32>,
     read_f64: FloatDecoder<f64>,
     float_size_f32: u64,
     float_size_f64: u64,
+    /// Strings are read into this buffer, which is reused for all of them.
+    buffer: Vec<u8>,
+    limits: DecodeLimits,
+    /// What has been counted against `limits.max_alloc`.
+    allocated: u64,
+    /// How many values the one being read is nested in.
+    depth: u64,
 }
 
-impl<'a, R: Read> Deserializer<'a, R> {
-    pub fn new(r: &'a mut R, size_limit: SizeLimit, float_enc: FloatEncoding) -> Deserializer<'a, R> {
-        let (read_f32, read_f64) = float_decoder(float_enc);
-        let (float_size_f32, float_size_f64) = float_sizes(float_enc);
+impl<'a, R: Reader> Deserializer<'a, R> {
+    pub fn new<C: Into<Config>>(r: &'a mut R, size_limit: SizeLimit, config: C) -> Deserializer<'a, R> {
+        let config = config.into();
+        let (read_f32, read_f64) = float_decoder(config.float_enc);
+        let (float_size_f32, float_size_f64) = float_sizes(config.float_enc);
         Deserializer {
-            reader: r,
+            reader: Recorder { reader: r, recorded: Vec::new(), recording: 0 },
             size_limit: size_limit,
             read: 0,
+            struct_enc: config.struct_enc,
+            canonical: config.canonical,
+            array_len_prefix: config.array_len_prefix,
             read_f32: read_f32,
             read_f64: read_f64,
             float_size_f32: float_size_f32 as u64,
             float_size_f64: float_size_f64 as u64,
+            buffer: Vec::new(),
+            limits: DecodeLimits::default(),
+            allocated: 0,
+            depth: 0,
         }
     }
 
+    /// Creates a Deserializer that fails with `SizeLimit` when the input
+    /// claims more than `limits` allow.
+    pub fn with_limits<C: Into<Config>>(r: &'a mut R, size_limit: SizeLimit, limits: DecodeLimits, config: C) -> Deserializer<'a, R> {
+        let mut deserializer = Deserializer::new(r, size_limit, config);
+        deserializer.limits = limits;
+        deserializer
+    }
+
     /// Returns the number of bytes read from the contained Reader.
     pub fn bytes_read(&self) -> u64 {
         self.read
     }
 
+    /// Exchanges the buffer that strings are read into, so that it can outlive the Deserializer.
+    pub(crate) fn swap_buffer(&mut self, buffer: &mut Vec<u8>) {
+        mem::swap(&mut self.buffer, buffer);
+    }
+
     #[inline]
     fn read_bytes(&mut self, count: u64) -> Result<(), DeserializeError> {
-        self.read += count;
+        self.read = match self.read.checked_add(count) {
+            Some(read) => read,
+            None => return Err(DeserializeError::SizeLimit),
+        };
         match self.size_limit {
             SizeLimit::Infinite => Ok(()),
             SizeLimit::Bounded(x) if self.read <= x => Ok(()),
@@ -168,46 +278,348 @@ impl<'a, R: Read> Deserializer<'a, R> {
         self.read_bytes(size_of::<T>() as u64)
     }*/
 
-    fn read_string(&mut self) -> DeserializeResult<String> {
-        let len = try!(serde::Deserialize::deserialize(self));
+    /// Counts the length of a string or byte buffer against the limits.
+    fn charge_str(&mut self, len: u64) -> DeserializeResult<()> {
+        let max = self.limits.max_str_len;
+        self.charge(len, max)
+    }
+
+    /// Counts the length of a sequence or map against the limits.
+    fn charge_seq(&mut self, len: usize) -> DeserializeResult<()> {
+        let max = self.limits.max_seq_len;
+        self.charge(len as u64, max)
+    }
+
+    fn charge(&mut self, len: u64, max: u64) -> DeserializeResult<()> {
+        match self.limits.charge(self.allocated, len, max) {
+            Some(allocated) => {
+                self.allocated = allocated;
+                Ok(())
+            }
+            None => Err(DeserializeError::SizeLimit),
+        }
+    }
+
+    /// Reads a value that is nested one level deeper than the current one.
+    pub(crate) fn nested<T, F>(&mut self, f: F) -> DeserializeResult<T>
+        where F: FnOnce(&mut Self) -> DeserializeResult<T>,
+    {
+        if self.depth >= self.limits.max_depth {
+            return Err(DeserializeError::DepthLimit);
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// The number of elements a visitor may preallocate for `len` elements.
+    ///
+    /// Only as many elements as there are bytes left in the size limit can be
+    /// more than zero sized, so a bogus length does not make us allocate more
+    /// than that.
+    fn size_hint(&self, len: usize) -> usize {
+        match self.size_limit {
+            SizeLimit::Bounded(x) => cmp::min(len as u64, x.saturating_sub(self.read)) as usize,
+            SizeLimit::Infinite => len,
+        }
+    }
+
+    fn read_vec(&mut self) -> DeserializeResult<Vec<u8>> {
+        let len = try!(serde::Deserialize::deserialize(&mut *self));
+        try!(self.charge_str(len));
         try!(self.read_bytes(len));
 
-        let mut buffer = Vec::new();
-        try!(self.reader.by_ref().take(len as u64).read_to_end(&mut buffer));
+        io::read_to_vec(&mut self.reader, len).map_err(DeserializeError::IoError)
+    }
 
-        String::from_utf8(buffer).map_err(|err|
+    fn read_str(&mut self) -> DeserializeResult<&str> {
+        let len = try!(serde::Deserialize::deserialize(&mut *self));
+        try!(self.charge_str(len));
+        try!(self.read_bytes(len));
+
+        self.buffer.clear();
+        try!(io::append_to_vec(&mut self.reader, len, &mut self.buffer));
+
+        str::from_utf8(&self.buffer).map_err(|err|
             DeserializeError::InvalidEncoding(InvalidEncoding {
                 desc: "error while decoding utf8 string",
                 detail: Some(format!("Deserialize error: {}", err))
             }))
     }
 
-    fn read_unsigned<T: ValueFrom<u64> + misc::Saturated + num_traits::Unsigned>(&mut self) -> DeserializeResult<T>
-    where RangeErrorKind: From<<T as ValueFrom<u64>>::Err> {
-        let r = leb128::read::unsigned(&mut self.reader);
-        self.map_leb128_result::<T, _>(r)
+    /// Reads and discards `count` bytes.
+    fn skip_bytes(&mut self, count: u64) -> DeserializeResult<()> {
+        try!(self.read_bytes(count));
+        io::skip(&mut self.reader, count).map_err(DeserializeError::IoError)
+    }
+
+    /// Visits `len` consecutive fields, like a tuple.
+    ///
+    /// `fields` are the names of the fields for error messages, it is empty for tuples.
+    fn deserialize_fields<'de, V>(&mut self,
+                                  fields: &'static [&'static str],
+                                  len: usize,
+                                  visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
+    {
+        struct TupleAccess<'a, 'b: 'a, R: Reader + 'b> {
+            deserializer: &'a mut Deserializer<'b, R>,
+            fields: &'static [&'static str],
+            len: usize,
+            index: usize,
+        }
+
+        impl<'de, 'a, 'b: 'a, R: Reader + 'b> serde::de::SeqAccess<'de> for TupleAccess<'a, 'b, R> {
+            type Error = DeserializeError;
+
+            fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+                where T: serde::de::DeserializeSeed<'de>,
+            {
+                if self.index == self.len {
+                    return Ok(None);
+                }
+                let index = self.index;
+                self.index += 1;
+                match seed.deserialize(&mut *self.deserializer) {
+                    Ok(value) => Ok(Some(value)),
+                    Err(err) => {
+                        let segment = match self.fields.get(index) {
+                            Some(name) => Segment::Field(name),
+                            None => Segment::Element(index),
+                        };
+                        Err(err.locate(self.deserializer.read, Some(segment)))
+                    }
+                }
+            }
+
+            fn size_hint(&self) -> Option<usize> {
+                Some(self.len - self.index)
+            }
+        }
+
+        self.nested(|deserializer| {
+            visitor.visit_seq(TupleAccess { deserializer: deserializer, fields: fields, len: len, index: 0 })
+        })
     }
 
-    fn read_signed<T: ValueFrom<i64> + misc::Saturated + num_traits::Signed>(&mut self) -> DeserializeResult<T>
-    where RangeErrorKind: From<<T as ValueFrom<i64>>::Err> {
-        let r = leb128::read::signed(&mut self.reader);
-        self.map_leb128_result::<T, _>(r)
+    /// Visits the fields of a struct that is prefixed with its encoded length.
+    fn deserialize_length_prefixed<'de, V>(&mut self,
+                                           fields: &'static [&'static str],
+                                           visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
+    {
+        /// Visits the fields of a length prefixed struct by their index.
+        struct StructAccess<'a, 'b: 'a, R: Reader + 'b> {
+            deserializer: &'a mut Deserializer<'b, R>,
+            fields: &'static [&'static str],
+            index: usize,
+            end: u64,
+        }
+
+        impl<'de, 'a, 'b: 'a, R: Reader + 'b> serde::de::MapAccess<'de> for StructAccess<'a, 'b, R> {
+            type Error = DeserializeError;
+
+            fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+                where K: serde::de::DeserializeSeed<'de>,
+            {
+                // Fields missing at the end were added by a newer writer.
+                if self.index < self.fields.len() && self.deserializer.read < self.end {
+                    let deserializer: serde::de::value::UsizeDeserializer<DeserializeError> =
+                        self.index.into_deserializer();
+                    let key = try!(seed.deserialize(deserializer));
+                    Ok(Some(key))
+                } else if self.index < self.fields.len() && self.deserializer.canonical {
+                    Err(DeserializeError::NonCanonical(InvalidEncoding {
+                        desc: "missing struct fields",
+                        detail: Some(format!("Expected {} fields, got {}", self.fields.len(), self.index))
+                    }))
+                } else {
+                    Ok(None)
+                }
+            }
+
+            fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+                where V: serde::de::DeserializeSeed<'de>,
+            {
+                let field = self.fields[self.index];
+                self.index += 1;
+                let value = try!(seed.deserialize(&mut *self.deserializer).map_err(|err|
+                    err.locate(self.deserializer.read, Some(Segment::Field(field)))));
+                Ok(value)
+            }
+        }
+
+        let len: u64 = try!(serde::Deserialize::deserialize(&mut *self));
+        let end = try!(self.read.checked_add(len).ok_or(DeserializeError::SizeLimit));
+        let value = try!(self.nested(|deserializer| {
+            visitor.visit_map(StructAccess { deserializer: deserializer, fields: fields, index: 0, end: end })
+        }));
+        let read = self.read;
+        if read < end && self.canonical {
+            Err(DeserializeError::NonCanonical(InvalidEncoding {
+                desc: "unknown struct fields",
+                detail: Some(format!("{} bytes after the last known field", end - read))
+            }))
+        } else if read <= end {
+            // Skip the trailing fields that were added by a newer writer.
+            try!(self.skip_bytes(end - read));
+            Ok(value)
+        } else {
+            Err(DeserializeError::InvalidEncoding(InvalidEncoding {
+                desc: "struct fields exceed the length prefix",
+                detail: Some(format!("Expected {} bytes, got {}", end, read))
+            }))
+        }
     }
 
-    fn map_leb128_result<T: ValueFrom<U> + misc::Saturated, U: ValueFrom<u64>>(&mut self, r: Result<(U, usize), leb128::read::Error>) -> DeserializeResult<T>
-    where RangeErrorKind: From<<T as ValueFrom<U>>::Err> {
+    /// Visits the fields of a struct that is prefixed with a presence bitmap.
+    fn deserialize_presence_bitmap<'de, V>(&mut self,
+                                           fields: &'static [&'static str],
+                                           visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
+    {
+        /// Visits the fields of a struct, taking the presence of `Option`s from the bitmap.
+        struct PresenceAccess<'a, 'b: 'a, R: Reader + 'b> {
+            deserializer: &'a mut Deserializer<'b, R>,
+            bitmap: Bitmap,
+            fields: &'static [&'static str],
+            index: usize,
+        }
+
+        impl<'de, 'a, 'b: 'a, R: Reader + 'b> serde::de::SeqAccess<'de> for PresenceAccess<'a, 'b, R> {
+            type Error = DeserializeError;
+
+            fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+                where T: serde::de::DeserializeSeed<'de>,
+            {
+                let field = match self.fields.get(self.index) {
+                    Some(field) => field,
+                    None => return Ok(None),
+                };
+                self.index += 1;
+                let probe = PresenceProbe { de: &mut *self.deserializer, bitmap: &mut self.bitmap };
+                let value = try!(seed.deserialize(probe).map_err(|err|
+                    err.locate(self.deserializer.read, Some(Segment::Field(field)))));
+                Ok(Some(value))
+            }
+
+            fn size_hint(&self) -> Option<usize> {
+                Some(self.fields.len() - self.index)
+            }
+        }
+
+        // Every field has at most one bit, seven to a byte.
+        let max_len = cmp::max(1, fields.len().div_ceil(7));
+        let mut bytes = Vec::new();
+        loop {
+            let byte: u8 = try!(serde::Deserialize::deserialize(&mut *self));
+            bytes.push(byte);
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if bytes.len() == max_len {
+                return Err(DeserializeError::InvalidEncoding(InvalidEncoding {
+                    desc: "presence bitmap too long",
+                    detail: Some(format!("Expected at most {} bytes for {} fields", max_len, fields.len()))
+                }));
+            }
+        }
+        if self.canonical && bytes.len() > 1 && bytes[bytes.len() - 1] == 0 {
+            return Err(DeserializeError::NonCanonical(InvalidEncoding {
+                desc: "overlong presence bitmap",
+                detail: None
+            }));
+        }
+
+        let canonical = self.canonical;
+        self.nested(|deserializer| {
+            let mut access = PresenceAccess {
+                deserializer: deserializer,
+                bitmap: Bitmap::from_encoded(bytes),
+                fields: fields,
+                index: 0,
+            };
+            let value = try!(visitor.visit_seq(&mut access));
+            if canonical && access.bitmap.has_remaining() {
+                return Err(DeserializeError::NonCanonical(InvalidEncoding {
+                    desc: "presence bits without an optional field",
+                    detail: None
+                }));
+            }
+            Ok(value)
+        })
+    }
+
+    fn read_unsigned<T: TryFrom<u64>>(&mut self) -> DeserializeResult<T> {
+        let r = varint::read_unsigned(&mut self.reader);
+        if let Ok((v, len)) = r {
+            try!(self.check_minimal(len, varint::unsigned_size(v)));
+        }
+        self.map_varint_result::<T, _>(r)
+    }
+
+    fn read_signed<T: TryFrom<i64>>(&mut self) -> DeserializeResult<T> {
+        let r = varint::read_signed(&mut self.reader);
+        if let Ok((v, len)) = r {
+            try!(self.check_minimal(len, varint::signed_size(v)));
+        }
+        self.map_varint_result::<T, _>(r)
+    }
+
+    fn read_unsigned128(&mut self) -> DeserializeResult<u128> {
+        let r = varint::read_unsigned128(&mut self.reader);
+        if let Ok((v, len)) = r {
+            try!(self.check_minimal(len, varint::unsigned_size128(v)));
+        }
+        self.map_varint_result::<u128, _>(r)
+    }
+
+    fn read_signed128(&mut self) -> DeserializeResult<i128> {
+        let r = varint::read_signed128(&mut self.reader);
+        if let Ok((v, len)) = r {
+            try!(self.check_minimal(len, varint::signed_size128(v)));
+        }
+        self.map_varint_result::<i128, _>(r)
+    }
+
+    /// In canonical mode, fails if an integer took `len` bytes rather than
+    /// the `minimal` it needs.
+    fn check_minimal(&self, len: usize, minimal: usize) -> DeserializeResult<()> {
+        if self.canonical && len != minimal {
+            Err(DeserializeError::NonCanonical(InvalidEncoding {
+                desc: "overlong integer",
+                detail: Some(format!("Expected {} bytes, got {}", minimal, len))
+            }))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// In canonical mode, fails if a float is a NaN other than the canonical one.
+    fn check_nan(&self, is_canonical: bool) -> DeserializeResult<()> {
+        if self.canonical && !is_canonical {
+            Err(DeserializeError::NonCanonical(InvalidEncoding {
+                desc: "NaN with a payload",
+                detail: None
+            }))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn map_varint_result<T: TryFrom<U>, U>(&mut self, r: Result<(U, usize), varint::ReadError>) -> DeserializeResult<T> {
         match r {
             Ok((v, bytes_read)) => {
                 self.read_bytes(bytes_read as u64)?;
-                // Ok(v.value_into().unwrap_or_saturate())
-                match v.value_into() {
+                match T::try_from(v) {
                     Ok(v) => Ok(v),
                     Err(_) => Err(DeserializeError::SizeLimit)
                 }
             }
             Err(e) => Err(match e {
-                leb128::read::Error::IoError(e) => DeserializeError::IoError(e),
-                leb128::read::Error::Overflow => DeserializeError::SizeLimit
+                varint::ReadError::IoError(e) => DeserializeError::IoError(e),
+                varint::ReadError::Overflow => DeserializeError::SizeLimit
             })
         }
     }
@@ -228,21 +640,23 @@ impl<'a, R: Read> Deserializer<'a, R> {
 // }
 
 
-impl<'a, R: Read> serde::Deserializer for Deserializer<'a, R> {
+impl<'de, 'a, 'b, R: Reader> serde::Deserializer<'de> for &'b mut Deserializer<'a, R> {
     type Error = DeserializeError;
 
     #[inline]
-    fn deserialize<V>(&mut self, _visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_any<V>(self, _visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        let message = "bincode does not support Deserializer::deserialize";
-        Err(DeserializeError::Serde(serde::de::value::Error::Custom(message.into())))
+        let message = "bincode does not support Deserializer::deserialize_any";
+        Err(serde::de::Error::custom(message))
     }
 
-    fn deserialize_bool<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn is_human_readable(&self) -> bool { false }
+
+    fn deserialize_bool<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        let value: u8 = try!(serde::Deserialize::deserialize(self));
+        let value: u8 = try!(serde::Deserialize::deserialize(&mut *self));
         match value {
             1 => visitor.visit_bool(true),
             0 => visitor.visit_bool(false),
@@ -259,130 +673,120 @@ impl<'a, R: Read> serde::Deserializer for Deserializer<'a, R> {
     // impl_nums!(f64, deserialize_f64, visit_f64, read_f64);
 
     #[inline]
-    fn deserialize_f32<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_f32<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         let bytes = self.float_size_f32;
         self.read_bytes(bytes)?;
-        visitor.visit_f32((self.read_f32)(&mut self.reader)?)
+        let value = (self.read_f32)(&mut self.reader)?;
+        try!(self.check_nan(!value.is_nan() || value.to_bits() == f32::NAN.to_bits()));
+        visitor.visit_f32(value)
     }
 
     #[inline]
-    fn deserialize_f64<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_f64<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         let bytes = self.float_size_f64;
         self.read_bytes(bytes)?;
-        visitor.visit_f64((self.read_f64)(&mut self.reader)?)
+        let value = (self.read_f64)(&mut self.reader)?;
+        try!(self.check_nan(!value.is_nan() || value.to_bits() == f64::NAN.to_bits()));
+        visitor.visit_f64(value)
     }
 
     #[inline]
-    fn deserialize_u8<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_u8<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         self.read_bytes(1)?;
-        visitor.visit_u8(try!(self.reader.read_u8()))
+        visitor.visit_u8(try!(io::read_u8(&mut self.reader)))
     }
 
     #[inline]
-    fn deserialize_u16<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_u16<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         visitor.visit_u16(self.read_unsigned()?)
     }
 
     #[inline]
-    fn deserialize_u32<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_u32<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         visitor.visit_u32(self.read_unsigned()?)
     }
 
     #[inline]
-    fn deserialize_u64<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_u64<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         visitor.visit_u64(self.read_unsigned()?)
     }
 
-    #[inline]
-    fn deserialize_usize<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_u128<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        visitor.visit_usize(self.read_unsigned()?)
+        visitor.visit_u128(try!(self.read_unsigned128()))
     }
 
     #[inline]
-    fn deserialize_i8<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_i8<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         self.read_bytes(1)?;
-        visitor.visit_i8(try!(self.reader.read_i8()))
+        visitor.visit_i8(try!(io::read_u8(&mut self.reader)) as i8)
     }
 
     #[inline]
-    fn deserialize_i16<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_i16<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         visitor.visit_i16(try!(self.read_signed()))
     }
 
     #[inline]
-    fn deserialize_i32<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_i32<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         visitor.visit_i32(try!(self.read_signed()))
     }
 
     #[inline]
-    fn deserialize_i64<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_i64<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         visitor.visit_i64(try!(self.read_signed()))
     }
 
-    #[inline]
-    fn deserialize_isize<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_i128<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        visitor.visit_isize(self.read_signed()?)
+        visitor.visit_i128(try!(self.read_signed128()))
     }
 
-    fn deserialize_unit<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_unit<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         visitor.visit_unit()
     }
 
-    fn deserialize_char<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_char<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        use std::str;
-
         let error = DeserializeError::InvalidEncoding(InvalidEncoding {
             desc: "Invalid char encoding",
             detail: None
         });
 
-        let mut buf = [0];
-
-        let _ = try!(self.reader.read(&mut buf[..]));
-        let first_byte = buf[0];
+        try!(self.read_bytes(1));
+        let first_byte = try!(io::read_u8(&mut self.reader));
         let width = utf8_char_width(first_byte);
         if width == 1 { return visitor.visit_char(first_byte as char) }
         if width == 0 { return Err(error)}
 
         let mut buf = [first_byte, 0, 0, 0];
-        {
-            let mut start = 1;
-            while start < width {
-                match try!(self.reader.read(&mut buf[start .. width])) {
-                    n if n == width - start => break,
-                    n if n < width - start => { start += n; }
-                    _ => return Err(error)
-                }
-            }
-        }
+        try!(self.read_bytes(width as u64 - 1));
+        try!(self.reader.read_exact(&mut buf[1 .. width]));
 
         let res = try!(match str::from_utf8(&buf[..width]).ok() {
             Some(s) => Ok(s.chars().next().unwrap()),
@@ -392,73 +796,68 @@ impl<'a, R: Read> serde::Deserializer for Deserializer<'a, R> {
         visitor.visit_char(res)
     }
 
-    fn deserialize_str<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_str<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        visitor.visit_str(&try!(self.read_string()))
+        visitor.visit_str(try!(self.read_str()))
     }
 
-    fn deserialize_string<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_string<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        visitor.visit_string(try!(self.read_string()))
+        // Visiting a borrowed string lets `deserialize_in_place` copy it
+        // into the buffer of the existing string.
+        visitor.visit_str(try!(self.read_str()))
     }
 
-    fn deserialize_bytes<V>(&mut self, visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_bytes<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        self.deserialize_seq(visitor)
+        visitor.visit_bytes(&try!(self.read_vec()))
     }
 
-    fn deserialize_enum<V>(&mut self,
-                     _enum: &'static str,
-                     _variants: &'static [&'static str],
-                     mut visitor: V) -> Result<V::Value, Self::Error>
-        where V: serde::de::EnumVisitor,
+    fn deserialize_byte_buf<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        visitor.visit(self)
+        visitor.visit_byte_buf(try!(self.read_vec()))
     }
 
-    fn deserialize_tuple<V>(&mut self,
-                      _len: usize,
-                      mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_enum<V>(self,
+                     _enum: &'static str,
+                     _variants: &'static [&'static str],
+                     visitor: V) -> Result<V::Value, Self::Error>
+        where V: serde::de::Visitor<'de>,
     {
-        struct TupleVisitor<'a, 'b: 'a, R: Read + 'b>(&'a mut Deserializer<'b, R>);
-
-        impl<'a, 'b: 'a, R: Read + 'b> serde::de::SeqVisitor for TupleVisitor<'a, 'b, R> {
-            type Error = DeserializeError;
-
-            fn visit<T>(&mut self) -> Result<Option<T>, Self::Error>
-                where T: serde::de::Deserialize,
-            {
-                let value = try!(serde::Deserialize::deserialize(self.0));
-                Ok(Some(value))
-            }
-
-            fn end(&mut self) -> Result<(), Self::Error> {
-                Ok(())
-            }
-        }
-
-        visitor.visit_seq(TupleVisitor(self))
+        self.nested(|deserializer| visitor.visit_enum(deserializer))
     }
 
-    fn deserialize_seq_fixed_size<V>(&mut self,
-                            _: usize,
-                            visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_tuple<V>(self,
+                      len: usize,
+                      visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        self.deserialize_seq(visitor)
+        if !self.array_len_prefix {
+            return self.deserialize_fields(&[], len, visitor);
+        }
+        // `BVec` asks for `usize::MAX` elements, only the written length
+        // tells how many there are.
+        let written: u64 = serde::Deserialize::deserialize(&mut *self)?;
+        if written > len as u64 {
+            return Err(DeserializeError::InvalidEncoding(InvalidEncoding {
+                desc: "tuple length mismatch",
+                detail: Some(format!("Expected at most {} elements, got {}", len, written))
+            }));
+        }
+        self.deserialize_fields(&[], written as usize, visitor)
     }
 
-    fn deserialize_option<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_option<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        let value: u8 = try!(serde::de::Deserialize::deserialize(self));
+        let value: u8 = try!(serde::de::Deserialize::deserialize(&mut *self));
         match value {
             0 => visitor.visit_none(),
-            1 => visitor.visit_some(self),
+            1 => self.nested(|deserializer| visitor.visit_some(deserializer)),
             _ => Err(DeserializeError::InvalidEncoding(InvalidEncoding {
                 desc: "invalid tag when decoding Option",
                 detail: Some(format!("Expected 0 or 1, got {}", value))
@@ -466,176 +865,249 @@ impl<'a, R: Read> serde::Deserializer for Deserializer<'a, R> {
         }
     }
 
-    fn deserialize_seq<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_seq<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        struct SeqVisitor<'a, 'b: 'a, R: Read + 'b> {
+        struct SeqAccess<'a, 'b: 'a, R: Reader + 'b> {
             deserializer: &'a mut Deserializer<'b, R>,
             len: usize,
+            index: usize,
         }
 
-        impl<'a, 'b: 'a, R: Read + 'b> serde::de::SeqVisitor for SeqVisitor<'a, 'b, R> {
+        impl<'de, 'a, 'b: 'a, R: Reader + 'b> serde::de::SeqAccess<'de> for SeqAccess<'a, 'b, R> {
             type Error = DeserializeError;
 
-            fn visit<T>(&mut self) -> Result<Option<T>, Self::Error>
-                where T: serde::de::Deserialize,
+            fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+                where T: serde::de::DeserializeSeed<'de>,
             {
                 if self.len > 0 {
                     self.len -= 1;
-                    let value = try!(serde::Deserialize::deserialize(self.deserializer));
+                    let index = self.index;
+                    self.index += 1;
+                    let value = try!(seed.deserialize(&mut *self.deserializer).map_err(|err|
+                        err.locate(self.deserializer.read, Some(Segment::Index(index)))));
                     Ok(Some(value))
                 } else {
                     Ok(None)
                 }
             }
 
-            fn end(&mut self) -> Result<(), Self::Error> {
-                if self.len == 0 {
-                    Ok(())
-                } else {
-                    Err(DeserializeError::Serde(serde::de::value::Error::Custom("expected end".into())))
-                }
+            fn size_hint(&self) -> Option<usize> {
+                Some(self.deserializer.size_hint(self.len))
             }
         }
 
-        let len = try!(serde::Deserialize::deserialize(self));
+        let len = try!(serde::Deserialize::deserialize(&mut *self));
+        try!(self.charge_seq(len));
 
-        visitor.visit_seq(SeqVisitor { deserializer: self, len: len })
+        self.nested(|deserializer| visitor.visit_seq(SeqAccess { deserializer: deserializer, len: len, index: 0 }))
     }
 
-    fn deserialize_map<V>(&mut self, mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_map<V>(self, visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        struct MapVisitor<'a, 'b: 'a, R: Read + 'b> {
+        struct MapAccess<'a, 'b: 'a, R: Reader + 'b> {
             deserializer: &'a mut Deserializer<'b, R>,
             len: usize,
+            index: usize,
+            /// The encoded previous key, in canonical mode.
+            last_key: Vec<u8>,
         }
 
-        impl<'a, 'b: 'a, R: Read + 'b> serde::de::MapVisitor for MapVisitor<'a, 'b, R> {
+        impl<'de, 'a, 'b: 'a, R: Reader + 'b> serde::de::MapAccess<'de> for MapAccess<'a, 'b, R> {
             type Error = DeserializeError;
 
-            fn visit_key<K>(&mut self) -> Result<Option<K>, Self::Error>
-                where K: serde::de::Deserialize,
+            fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+                where K: serde::de::DeserializeSeed<'de>,
             {
-                if self.len > 0 {
-                    self.len -= 1;
-                    let key = try!(serde::Deserialize::deserialize(self.deserializer));
-                    Ok(Some(key))
-                } else {
-                    Ok(None)
+                if self.len == 0 {
+                    return Ok(None);
+                }
+                self.len -= 1;
+                if !self.deserializer.canonical {
+                    let key = try!(seed.deserialize(&mut *self.deserializer).map_err(|err|
+                        err.locate(self.deserializer.read, Some(Segment::Index(self.index)))));
+                    return Ok(Some(key));
                 }
+
+                // The keys have to be in strictly ascending order of their encoding.
+                let start = self.deserializer.reader.start_recording();
+                let key = seed.deserialize(&mut *self.deserializer);
+                let ascending = {
+                    let recorded = self.deserializer.reader.stop_recording(start);
+                    let ascending = self.index == 0 || &self.last_key[..] < recorded;
+                    self.last_key.clear();
+                    self.last_key.extend_from_slice(recorded);
+                    ascending
+                };
+                let key = try!(key.and_then(|key| if ascending {
+                    Ok(key)
+                } else {
+                    Err(DeserializeError::NonCanonical(InvalidEncoding {
+                        desc: "map keys out of order",
+                        detail: None
+                    }))
+                }).map_err(|err| err.locate(self.deserializer.read, Some(Segment::Index(self.index)))));
+                Ok(Some(key))
             }
 
-            fn visit_value<V>(&mut self) -> Result<V, Self::Error>
-                where V: serde::de::Deserialize,
+            fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+                where V: serde::de::DeserializeSeed<'de>,
             {
-                let value = try!(serde::Deserialize::deserialize(self.deserializer));
+                let index = self.index;
+                self.index += 1;
+                let value = try!(seed.deserialize(&mut *self.deserializer).map_err(|err|
+                    err.locate(self.deserializer.read, Some(Segment::Index(index)))));
                 Ok(value)
             }
 
-            fn end(&mut self) -> Result<(), Self::Error> {
-                if self.len == 0 {
-                    Ok(())
-                } else {
-                    Err(DeserializeError::Serde(serde::de::value::Error::Custom("expected end".into())))
-                }
+            fn size_hint(&self) -> Option<usize> {
+                Some(self.deserializer.size_hint(self.len))
             }
         }
 
-        let len = try!(serde::Deserialize::deserialize(self));
+        let len = try!(serde::Deserialize::deserialize(&mut *self));
+        try!(self.charge_seq(len));
 
-        visitor.visit_map(MapVisitor { deserializer: self, len: len })
+        self.nested(|deserializer| visitor.visit_map(MapAccess { deserializer: deserializer, len: len, index: 0, last_key: Vec::new() }))
     }
 
-    fn deserialize_struct<V>(&mut self,
-                       _name: &str,
+    fn deserialize_struct<V>(self,
+                       name: &'static str,
                        fields: &'static [&'static str],
                        visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+        where V: serde::de::Visitor<'de>,
     {
-        self.deserialize_tuple(fields.len(), visitor)
+        let result = match self.struct_enc {
+            StructEncoding::Tuple => self.deserialize_fields(fields, fields.len(), visitor),
+            StructEncoding::LengthPrefixed => self.deserialize_length_prefixed(fields, visitor),
+            StructEncoding::PresenceBitmap => self.deserialize_presence_bitmap(fields, visitor),
+        };
+        result.map_err(|err| err.locate(self.read, Some(Segment::Struct(name))))
     }
 
-    fn deserialize_struct_field<V>(&mut self,
-                                   _visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_identifier<V>(self,
+                                 _visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        let message = "bincode does not support Deserializer::deserialize_struct_field";
-        Err(DeserializeError::Serde(serde::de::value::Error::Custom(message.into())))
+        let message = "bincode does not support Deserializer::deserialize_identifier";
+        Err(serde::de::Error::custom(message))
     }
 
-    fn deserialize_newtype_struct<V>(&mut self,
-                               _name: &str,
-                               mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+    fn deserialize_newtype_struct<V>(self,
+                               _name: &'static str,
+                               visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
-        visitor.visit_newtype_struct(self)
+        self.nested(|deserializer| visitor.visit_newtype_struct(deserializer))
     }
 
-    fn deserialize_unit_struct<V>(&mut self,
+    fn deserialize_unit_struct<V>(self,
                                   _name: &'static str,
-                                  mut visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+                                  visitor: V) -> DeserializeResult<V::Value>
+        where V: serde::de::Visitor<'de>,
     {
         visitor.visit_unit()
     }
 
-    fn deserialize_tuple_struct<V>(&mut self,
+    fn deserialize_tuple_struct<V>(self,
                                    _name: &'static str,
                                    len: usize,
                                    visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+        where V: serde::de::Visitor<'de>,
     {
-        self.deserialize_tuple(len, visitor)
+        self.deserialize_fields(&[], len, visitor)
     }
 
-    fn deserialize_ignored_any<V>(&mut self,
+    fn deserialize_ignored_any<V>(self,
                                   _visitor: V) -> DeserializeResult<V::Value>
-        where V: serde::de::Visitor,
+        where V: serde::de::Visitor<'de>,
     {
         let message = "bincode does not support Deserializer::deserialize_ignored_any";
-        Err(DeserializeError::Serde(serde::de::value::Error::Custom(message.into())))
+        Err(serde::de::Error::custom(message))
     }
 }
 
-impl<'a, R: Read> serde::de::VariantVisitor for Deserializer<'a, R> {
+impl<'de, 'a, 'b, R: Reader> serde::de::EnumAccess<'de> for &'b mut Deserializer<'a, R> {
     type Error = DeserializeError;
+    type Variant = Self;
 
-    fn visit_variant<V>(&mut self) -> Result<V, Self::Error>
-        where V: serde::Deserialize,
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self), Self::Error>
+        where V: serde::de::DeserializeSeed<'de>,
     {
-        let index: u32 = try!(serde::Deserialize::deserialize(self));
-        let mut deserializer = (index as usize).into_deserializer();
-        let attempt: Result<V, serde::de::value::Error> = serde::Deserialize::deserialize(&mut deserializer);
-        Ok(try!(attempt))
+        let index: u32 = try!(serde::Deserialize::deserialize(&mut *self));
+        let deserializer: serde::de::value::U32Deserializer<DeserializeError> = index.into_deserializer();
+        let variant = try!(seed.deserialize(deserializer));
+        Ok((variant, self))
     }
+}
 
-    fn visit_unit(&mut self) -> Result<(), Self::Error> {
+impl<'de, 'a, 'b, R: Reader> serde::de::VariantAccess<'de> for &'b mut Deserializer<'a, R> {
+    type Error = DeserializeError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
         Ok(())
     }
 
-    fn visit_newtype<T>(&mut self) -> Result<T, Self::Error>
-        where T: serde::de::Deserialize,
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+        where T: serde::de::DeserializeSeed<'de>,
     {
-        serde::de::Deserialize::deserialize(self)
+        seed.deserialize(self)
     }
 
-    fn visit_tuple<V>(&mut self,
-                      len: usize,
-                      visitor: V) -> Result<V::Value, Self::Error>
-        where V: serde::de::Visitor,
+    fn tuple_variant<V>(self,
+                        len: usize,
+                        visitor: V) -> Result<V::Value, Self::Error>
+        where V: serde::de::Visitor<'de>,
     {
-        serde::de::Deserializer::deserialize_tuple(self, len, visitor)
+        self.deserialize_fields(&[], len, visitor)
     }
 
-    fn visit_struct<V>(&mut self,
-                       fields: &'static [&'static str],
-                       visitor: V) -> Result<V::Value, Self::Error>
-        where V: serde::de::Visitor,
+    fn struct_variant<V>(self,
+                         fields: &'static [&'static str],
+                         visitor: V) -> Result<V::Value, Self::Error>
+        where V: serde::de::Visitor<'de>,
     {
-        serde::de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+        self.deserialize_fields(fields, fields.len(), visitor)
+    }
+}
+/// A Reader that keeps a copy of the bytes that are read while recording.
+///
+/// Recordings nest, the bytes of an inner one are part of the outer one.
+struct Recorder<'a, R: 'a> {
+    reader: &'a mut R,
+    recorded: Vec<u8>,
+    /// The number of recordings in progress.
+    recording: usize,
+}
+
+impl<'a, R> Recorder<'a, R> {
+    /// Starts a recording and returns where its bytes will start.
+    fn start_recording(&mut self) -> usize {
+        if self.recording == 0 {
+            self.recorded.clear();
+        }
+        self.recording += 1;
+        self.recorded.len()
+    }
+
+    /// Stops the recording that started at `start` and returns its bytes.
+    fn stop_recording(&mut self, start: usize) -> &[u8] {
+        self.recording -= 1;
+        &self.recorded[start..]
     }
 }
+
+impl<'a, R: Reader> Reader for Recorder<'a, R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoError> {
+        try!(self.reader.read_exact(buf));
+        if self.recording > 0 {
+            self.recorded.extend_from_slice(buf);
+        }
+        Ok(())
+    }
+}
+
 static UTF8_CHAR_WIDTH: [u8; 256] = [
 1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,
 1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1, // 0x1F
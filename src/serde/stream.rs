@@ -0,0 +1,181 @@
+//! Decoding a stream of consecutive values.
+
+use std::cmp;
+use std::marker::PhantomData;
+
+use ::{SizeLimit, Config};
+use io::{self, IoError, Reader};
+#[cfg(not(feature = "std"))]
+use prelude::*;
+
+use serde_crate as serde;
+
+use super::{Deserializer, DeserializeError, DeserializeResult};
+
+/// An iterator over the values that were encoded back to back into a `Reader`.
+///
+/// The iteration ends when the reader is at its end between two values; a
+/// value that is cut short is an `IoError`. After an error the iterator ends,
+/// as the reader is left in an invalid state.
+///
+/// The end is only noticed when a value asks for its first byte, so a value
+/// that takes no bytes, like `()`, is decoded without reading anything. A
+/// stream of such values never ends, but e.g. `take` and `into_inner` leave
+/// the reader right behind the values that were taken.
+///
+/// Every value must fit into `record_limit`, and all of them together into
+/// `total_limit`. Error offsets count from the start of the stream.
+///
+/// ```rust
+/// use mincode::{StreamDeserializer, SizeLimit, FloatEncoding};
+/// use mincode::serde::serialize;
+///
+/// let mut bytes = serialize(&(1u8, "one"), SizeLimit::Infinite, FloatEncoding::Normal).unwrap();
+/// bytes.extend(serialize(&(2u8, "two"), SizeLimit::Infinite, FloatEncoding::Normal).unwrap());
+///
+/// let stream = StreamDeserializer::new(&bytes[..], SizeLimit::Bounded(16), SizeLimit::Infinite, FloatEncoding::Normal);
+/// let values: Vec<(u8, String)> = stream.map(Result::unwrap).collect();
+/// assert_eq!(values, vec![(1, "one".to_string()), (2, "two".to_string())]);
+/// ```
+pub struct StreamDeserializer<R, T> {
+    reader: R,
+    record_limit: SizeLimit,
+    total_limit: SizeLimit,
+    config: Config,
+    read: u64,
+    done: bool,
+    /// The scratch space for strings, shared by all values.
+    buffer: Vec<u8>,
+    marker: PhantomData<T>,
+}
+
+impl<R: Reader, T: serde::de::DeserializeOwned> StreamDeserializer<R, T> {
+    pub fn new<C: Into<Config>>(reader: R, record_limit: SizeLimit, total_limit: SizeLimit, config: C) -> StreamDeserializer<R, T> {
+        StreamDeserializer {
+            reader: reader,
+            record_limit: record_limit,
+            total_limit: total_limit,
+            config: config.into(),
+            read: 0,
+            done: false,
+            buffer: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of bytes read from the contained Reader.
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    /// Returns the contained Reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// The size limit of the next value, what is left of the total limit
+    /// if that is smaller than the limit per value.
+    fn next_limit(&self) -> SizeLimit {
+        match (self.record_limit, self.total_limit) {
+            (SizeLimit::Infinite, SizeLimit::Infinite) => SizeLimit::Infinite,
+            (SizeLimit::Bounded(record), SizeLimit::Infinite) => SizeLimit::Bounded(record),
+            (SizeLimit::Infinite, SizeLimit::Bounded(total)) => SizeLimit::Bounded(total - self.read),
+            (SizeLimit::Bounded(record), SizeLimit::Bounded(total)) =>
+                SizeLimit::Bounded(cmp::min(record, total - self.read)),
+        }
+    }
+
+    fn next_value(&mut self) -> DeserializeResult<Option<T>> {
+        let start = self.read;
+        let limit = self.next_limit();
+        let mut reader = EndProbe { reader: &mut self.reader, started: false, at_end: false };
+        let (result, read) = {
+            let mut deserializer = Deserializer::new(&mut reader, limit, self.config);
+            deserializer.swap_buffer(&mut self.buffer);
+            let result = serde::Deserialize::deserialize(&mut deserializer);
+            deserializer.swap_buffer(&mut self.buffer);
+            (result, deserializer.bytes_read())
+        };
+        self.read += read;
+
+        if reader.at_end {
+            return Ok(None);
+        }
+        let result = match result {
+            // A stream whose total limit is used up ends there, but the next
+            // value is only too big if there is one.
+            Err(err) if !reader.started && is_size_limit(&err) => match io::read_u8_or_eof(&mut *reader.reader) {
+                Ok(None) => return Ok(None),
+                Ok(Some(_)) => Err(err),
+                Err(err) => Err(DeserializeError::IoError(err)),
+            },
+            result => result,
+        };
+
+        result.map(Some).map_err(|err| match err.locate(read, None) {
+            DeserializeError::At(err, mut position) => {
+                position.offset += start;
+                DeserializeError::At(err, position)
+            }
+            err => err,
+        })
+    }
+}
+
+impl<R: Reader, T: serde::de::DeserializeOwned> Iterator for StreamDeserializer<R, T> {
+    type Item = DeserializeResult<T>;
+
+    fn next(&mut self) -> Option<DeserializeResult<T>> {
+        if self.done {
+            return None;
+        }
+        match self.next_value() {
+            Ok(Some(value)) => Some(Ok(value)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn is_size_limit(err: &DeserializeError) -> bool {
+    match *err {
+        DeserializeError::SizeLimit => true,
+        DeserializeError::At(ref err, _) => is_size_limit(err),
+        _ => false,
+    }
+}
+
+/// A Reader that notes whether the stream is at its end where a value asks
+/// for its first byte.
+struct EndProbe<'a, R: 'a> {
+    reader: &'a mut R,
+    started: bool,
+    at_end: bool,
+}
+
+impl<'a, R: Reader> Reader for EndProbe<'a, R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoError> {
+        if buf.is_empty() || self.started {
+            return self.reader.read_exact(buf);
+        }
+        self.started = true;
+        match io::read_u8_or_eof(&mut *self.reader) {
+            Ok(Some(byte)) => {
+                buf[0] = byte;
+                self.reader.read_exact(&mut buf[1..])
+            }
+            Ok(None) => {
+                self.at_end = true;
+                // Fails the same way, as there is nothing left to read.
+                self.reader.read_exact(buf)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
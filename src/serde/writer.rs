@@ -1,16 +1,16 @@
-use std::error::Error;
 use std::fmt;
-use std::io::Error as IoError;
-use std::io::Write;
 use std::u32;
 
 use serde_crate as serde;
 
-use byteorder::WriteBytesExt;
-
-use leb128;
-
 use float::*;
+use varint;
+use error::{ErrorKind, Segment, StdError as Error};
+use io::{IoError, Writer};
+#[cfg(not(feature = "std"))]
+use prelude::*;
+use ::{Config, StructEncoding, ErrorPosition};
+use super::presence::{Bitmap, OptionProbe};
 
 pub type SerializeResult<T> = Result<T, SerializeError>;
 
@@ -25,8 +25,13 @@ pub enum SerializeError {
     /// This error is returned before any bytes are written to the
     /// output `Writer`.
     SizeLimit,
+    /// An object did not fit into the buffer it was encoded into, it needs
+    /// `required` bytes.
+    BufferTooSmall { required: u64 },
     /// A custom error message
-    Custom(String)
+    Custom(String),
+    /// Another error, together with where in the output it occurred.
+    At(Box<SerializeError>, ErrorPosition),
 }
 
 fn wrap_io(err: IoError) -> SerializeError {
@@ -39,13 +44,16 @@ fn wrap_io(err: IoError) -> SerializeError {
 /// For most cases, prefer the `encode_into` function.
 pub struct Serializer<'a, W: 'a> {
     writer: &'a mut W,
+    written: u64,
+    size_limit: u64,
+    config: Config,
     write_f32: FloatEncoder<f32>,
     write_f64: FloatEncoder<f64>,
 }
 
 impl serde::ser::Error for SerializeError {
-    fn custom<T: Into<String>>(msg: T) -> Self {
-        SerializeError::Custom(msg.into())
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerializeError::Custom(msg.to_string())
     }
 }
 
@@ -55,6 +63,9 @@ impl fmt::Display for SerializeError {
             SerializeError::IoError(ref err) => write!(f, "IoError: {}", err),
             SerializeError::Custom(ref s) => write!(f, "Custom Error {}", s),
             SerializeError::SizeLimit => write!(f, "SizeLimit"),
+            SerializeError::BufferTooSmall { required } =>
+                write!(f, "BufferTooSmall: {} bytes required", required),
+            SerializeError::At(ref err, ref position) => write!(f, "{} {}", err, position),
         }
     }
 }
@@ -64,7 +75,9 @@ impl Error for SerializeError {
         match *self {
             SerializeError::IoError(ref err) => Error::description(err),
             SerializeError::SizeLimit => "the size limit for decoding has been reached",
+            SerializeError::BufferTooSmall { .. } => "the buffer is too small for the encoded object",
             SerializeError::Custom(_) => "a custom serialization error was reported",
+            SerializeError::At(ref err, _) => err.description(),
         }
     }
 
@@ -72,298 +85,530 @@ impl Error for SerializeError {
         match *self {
             SerializeError::IoError(ref err) => err.cause(),
             SerializeError::SizeLimit => None,
+            SerializeError::BufferTooSmall { .. } => None,
             SerializeError::Custom(_) => None,
+            SerializeError::At(ref err, _) => err.cause(),
+        }
+    }
+}
+
+impl SerializeError {
+    /// Returns where in the output the error occurred.
+    pub fn position(&self) -> Option<&ErrorPosition> {
+        match *self {
+            SerializeError::At(_, ref position) => Some(position),
+            _ => None,
         }
     }
+
+    /// Returns the error without its position.
+    pub fn into_inner(self) -> SerializeError {
+        match self {
+            SerializeError::At(err, _) => *err,
+            err => err,
+        }
+    }
+
+    /// Attaches the offset if the error has no position yet, and prepends
+    /// the segment to its path.
+    pub(crate) fn locate(self, offset: u64, segment: Option<Segment>) -> SerializeError {
+        let (err, mut position) = match self {
+            SerializeError::At(err, position) => (err, position),
+            err => (Box::new(err), ErrorPosition::new(offset)),
+        };
+        if let Some(segment) = segment {
+            position.push_front(segment);
+        }
+        SerializeError::At(err, position)
+    }
+}
+
+impl From<SerializeError> for ::Error {
+    fn from(err: SerializeError) -> ::Error {
+        let kind = match err {
+            SerializeError::IoError(err) => ErrorKind::Io(err),
+            SerializeError::SizeLimit => ErrorKind::SizeLimit,
+            SerializeError::BufferTooSmall { required } => ErrorKind::BufferTooSmall { required: required },
+            SerializeError::Custom(msg) => ErrorKind::Custom(msg),
+            SerializeError::At(err, position) => {
+                let err = ::Error::from(*err);
+                return ::Error::new(err.into_kind(), Some(position));
+            }
+        };
+        ::Error::from(kind)
+    }
 }
 
-impl<'a, W: Write> Serializer<'a, W> {
-    pub fn new(w: &'a mut W, float_enc: FloatEncoding) -> Serializer<'a, W> {
-        let (write_f32, write_f64) = float_encoder(float_enc);
+impl<'a, W: Writer> Serializer<'a, W> {
+    pub fn new<C: Into<Config>>(w: &'a mut W, config: C) -> Serializer<'a, W> {
+        let config = config.into();
+        let (write_f32, write_f64) = float_encoder(config.float_enc);
         Serializer {
             writer: w,
+            written: 0,
+            size_limit: u64::MAX,
+            config: config,
             write_f32: write_f32,
             write_f64: write_f64,
         }
     }
 
-    fn add_enum_tag(&mut self, tag: usize) -> SerializeResult<()> {
-        self.write_unsigned(tag as u32)
+    /// Creates a Serializer that fails with `SizeLimit` as soon as more than
+    /// `limit` bytes would be written.
+    ///
+    /// The bytes before the limit was crossed have been written when that
+    /// happens, so write into a buffer if they must not reach their destination.
+    pub fn bounded<C: Into<Config>>(w: &'a mut W, limit: u64, config: C) -> Serializer<'a, W> {
+        let mut serializer = Serializer::new(w, config);
+        serializer.size_limit = limit;
+        serializer
+    }
+
+    /// Returns the number of bytes written to the contained Writer.
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    fn add_enum_tag(&mut self, tag: u32) -> SerializeResult<()> {
+        self.write_unsigned(tag)
+    }
+
+    /// Checks that `size` more bytes fit into the size limit.
+    fn reserve(&mut self, size: usize) -> SerializeResult<()> {
+        if self.written + size as u64 <= self.size_limit {
+            Ok(())
+        } else {
+            Err(SerializeError::SizeLimit)
+        }
+    }
+
+    fn write_byte(&mut self, v: u8) -> SerializeResult<()> {
+        try!(self.reserve(1));
+        try!(self.writer.write_all(&[v]).map_err(wrap_io));
+        self.written += 1;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, v: &[u8]) -> SerializeResult<()> {
+        try!(self.reserve(v.len()));
+        try!(self.writer.write_all(v).map_err(wrap_io));
+        self.written += v.len() as u64;
+        Ok(())
     }
 
     fn write_unsigned<T: Into<u64>>(&mut self, v: T) -> SerializeResult<()> {
-        leb128::write::unsigned(&mut self.writer, v.into()).map(|_| ()).map_err(wrap_io)
+        let v = v.into();
+        try!(self.reserve(varint::unsigned_size(v)));
+        let n = try!(varint::write_unsigned(self.writer, v).map_err(wrap_io));
+        self.written += n as u64;
+        Ok(())
     }
     
     fn write_signed<T: Into<i64>>(&mut self, v: T) -> SerializeResult<()> {
-        leb128::write::signed(&mut self.writer, v.into()).map(|_| ()).map_err(wrap_io)
+        let v = v.into();
+        try!(self.reserve(varint::signed_size(v)));
+        let n = try!(varint::write_signed(self.writer, v).map_err(wrap_io));
+        self.written += n as u64;
+        Ok(())
     }
 }
 
-impl<'a, W: Write> serde::Serializer for Serializer<'a, W> {
+impl<'a, 'b, W: Writer> serde::Serializer for &'a mut Serializer<'b, W> {
+    type Ok = ();
     type Error = SerializeError;
-    type SeqState = ();
-    type TupleState = ();
-    type TupleStructState = ();
-    type TupleVariantState = ();
-    type MapState = ();
-    type StructState = ();
-    type StructVariantState = ();
+    type SerializeSeq = SeqCompound<'a, 'b, W>;
+    type SerializeTuple = Compound<'a, Serializer<'b, W>>;
+    type SerializeTupleStruct = Compound<'a, Serializer<'b, W>>;
+    type SerializeTupleVariant = Compound<'a, Serializer<'b, W>>;
+    type SerializeMap = SeqCompound<'a, 'b, W>;
+    type SerializeStruct = StructCompound<'a, 'b, W>;
+    type SerializeStructVariant = Compound<'a, Serializer<'b, W>>;
 
-    fn serialize_unit(&mut self) -> SerializeResult<()> { Ok(()) }
+    fn is_human_readable(&self) -> bool { false }
 
-    fn serialize_unit_struct(&mut self, _: &'static str) -> SerializeResult<()> { Ok(()) }
+    fn serialize_unit(self) -> SerializeResult<()> { Ok(()) }
 
-    fn serialize_bool(&mut self, v: bool) -> SerializeResult<()> {
-        self.writer.write_u8(if v {1} else {0}).map_err(wrap_io)
+    fn serialize_unit_struct(self, _: &'static str) -> SerializeResult<()> { Ok(()) }
+
+    fn serialize_bool(self, v: bool) -> SerializeResult<()> {
+        self.write_byte(if v {1} else {0})
     }
 
-    fn serialize_u8(&mut self, v: u8) -> SerializeResult<()> {
-        self.writer.write_u8(v).map_err(wrap_io)
+    fn serialize_u8(self, v: u8) -> SerializeResult<()> {
+        self.write_byte(v)
     }
 
-    fn serialize_u16(&mut self, v: u16) -> SerializeResult<()> {
+    fn serialize_u16(self, v: u16) -> SerializeResult<()> {
         self.write_unsigned(v)
     }
 
-    fn serialize_u32(&mut self, v: u32) -> SerializeResult<()> {
+    fn serialize_u32(self, v: u32) -> SerializeResult<()> {
         self.write_unsigned(v)
     }
 
-    fn serialize_u64(&mut self, v: u64) -> SerializeResult<()> {
+    fn serialize_u64(self, v: u64) -> SerializeResult<()> {
         self.write_unsigned(v)
     }
 
-    fn serialize_usize(&mut self, v: usize) -> SerializeResult<()> {
-        self.write_unsigned(v as u64)
+    fn serialize_u128(self, v: u128) -> SerializeResult<()> {
+        try!(self.reserve(varint::unsigned_size128(v)));
+        let n = try!(varint::write_unsigned128(self.writer, v).map_err(wrap_io));
+        self.written += n as u64;
+        Ok(())
     }
 
-    fn serialize_i8(&mut self, v: i8) -> SerializeResult<()> {
-        self.writer.write_i8(v).map_err(wrap_io)
+    fn serialize_i8(self, v: i8) -> SerializeResult<()> {
+        self.write_byte(v as u8)
     }
 
-    fn serialize_i16(&mut self, v: i16) -> SerializeResult<()> {
+    fn serialize_i16(self, v: i16) -> SerializeResult<()> {
         self.write_signed(v)
     }
 
-    fn serialize_i32(&mut self, v: i32) -> SerializeResult<()> {
+    fn serialize_i32(self, v: i32) -> SerializeResult<()> {
         self.write_signed(v)
     }
 
-    fn serialize_i64(&mut self, v: i64) -> SerializeResult<()> {
+    fn serialize_i64(self, v: i64) -> SerializeResult<()> {
         self.write_signed(v)
     }
 
-    fn serialize_isize(&mut self, v: isize) -> SerializeResult<()> {
-        self.write_signed(v as i64)
+    fn serialize_i128(self, v: i128) -> SerializeResult<()> {
+        try!(self.reserve(varint::signed_size128(v)));
+        let n = try!(varint::write_signed128(self.writer, v).map_err(wrap_io));
+        self.written += n as u64;
+        Ok(())
     }
 
-    fn serialize_f32(&mut self, v: f32) -> SerializeResult<()> {
+    fn serialize_f32(self, v: f32) -> SerializeResult<()> {
         // self.writer.write_f32::<BigEndian>(v).map_err(wrap_io)
-        (self.write_f32)(&mut self.writer, v).map_err(wrap_io)
+        let size = float_sizes(self.config.float_enc).0;
+        try!(self.reserve(size));
+        // NaNs may carry any payload, canonical encoding only has the one NaN.
+        let v = if self.config.canonical && v.is_nan() { f32::NAN } else { v };
+        try!((self.write_f32)(self.writer, v).map_err(wrap_io));
+        self.written += size as u64;
+        Ok(())
     }
 
-    fn serialize_f64(&mut self, v: f64) -> SerializeResult<()> {
+    fn serialize_f64(self, v: f64) -> SerializeResult<()> {
         // self.writer.write_f64::<BigEndian>(v).map_err(wrap_io)
-        (self.write_f64)(&mut self.writer, v).map_err(wrap_io)
+        let size = float_sizes(self.config.float_enc).1;
+        try!(self.reserve(size));
+        let v = if self.config.canonical && v.is_nan() { f64::NAN } else { v };
+        try!((self.write_f64)(self.writer, v).map_err(wrap_io));
+        self.written += size as u64;
+        Ok(())
     }
 
-    fn serialize_str(&mut self, v: &str) -> SerializeResult<()> {
-        try!(self.serialize_usize(v.len()));
-        self.writer.write_all(v.as_bytes()).map_err(SerializeError::IoError)
+    fn serialize_str(self, v: &str) -> SerializeResult<()> {
+        try!(self.write_unsigned(v.len() as u64));
+        self.write_bytes(v.as_bytes())
     }
 
-    fn serialize_char(&mut self, c: char) -> SerializeResult<()> {
-        self.writer.write_all(encode_utf8(c).as_slice()).map_err(SerializeError::IoError)
+    fn serialize_char(self, c: char) -> SerializeResult<()> {
+        self.write_bytes(encode_utf8(c).as_slice())
     }
 
-    fn serialize_bytes(&mut self, v: &[u8]) -> SerializeResult<()> {
-        let mut state = try!(self.serialize_seq(Some(v.len())));
-        for c in v {
-            try!(self.serialize_seq_elt(&mut state, c));
-        }
-        self.serialize_seq_end(state)
+    fn serialize_bytes(self, v: &[u8]) -> SerializeResult<()> {
+        try!(self.write_unsigned(v.len() as u64));
+        self.write_bytes(v)
     }
 
-    fn serialize_none(&mut self) -> SerializeResult<()> {
-        self.writer.write_u8(0).map_err(wrap_io)
+    fn serialize_none(self) -> SerializeResult<()> {
+        self.write_byte(0)
     }
 
-    fn serialize_some<T>(&mut self, v: T) -> SerializeResult<()>
-        where T: serde::Serialize,
+    fn serialize_some<T>(self, v: &T) -> SerializeResult<()>
+        where T: ?Sized + serde::Serialize,
     {
-        try!(self.writer.write_u8(1).map_err(wrap_io));
+        try!(self.write_byte(1));
         v.serialize(self)
     }
 
-    fn serialize_seq(&mut self, len: Option<usize>) -> SerializeResult<()> {
-        let len = len.expect("do not know how to serialize a sequence with no length");
-        self.serialize_usize(len)
-    }
-
-    fn serialize_seq_elt<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
-    }
-
-    fn serialize_seq_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_seq(self, len: Option<usize>) -> SerializeResult<Self::SerializeSeq> {
+        SeqCompound::new(self, len)
     }
 
-    fn serialize_seq_fixed_size(&mut self, len: usize) -> SerializeResult<()> {
-        self.serialize_seq(Some(len))
+    fn serialize_tuple(self, len: usize) -> SerializeResult<Self::SerializeTuple> {
+        if self.config.array_len_prefix {
+            self.write_unsigned(len as u64)?;
+        }
+        Ok(Compound::new(self))
     }
 
-    fn serialize_tuple(&mut self, _len: usize) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> SerializeResult<Self::SerializeTupleStruct> {
+        Ok(Compound::new(self))
     }
 
-    fn serialize_tuple_elt<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
+    fn serialize_tuple_variant(self,
+                              _name: &'static str,
+                              variant_index: u32,
+                              _variant: &'static str,
+                              _len: usize) -> SerializeResult<Self::SerializeTupleVariant>
     {
-        value.serialize(self)
+        try!(self.add_enum_tag(variant_index));
+        Ok(Compound::new(self))
     }
 
-    fn serialize_tuple_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_map(self, len: Option<usize>) -> SerializeResult<Self::SerializeMap> {
+        SeqCompound::new_map(self, len)
     }
 
-    fn serialize_tuple_struct(&mut self, _name: &'static str, _len: usize) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_struct(self, name: &'static str, _len: usize) -> SerializeResult<Self::SerializeStruct> {
+        let (fields, bitmap) = match self.config.struct_enc {
+            StructEncoding::Tuple => (None, None),
+            StructEncoding::LengthPrefixed => (Some(Vec::new()), None),
+            StructEncoding::PresenceBitmap => (Some(Vec::new()), Some(Bitmap::default())),
+        };
+        Ok(StructCompound { ser: self, name: name, fields: fields, bitmap: bitmap })
     }
 
-    fn serialize_tuple_struct_elt<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
+    fn serialize_struct_variant(self,
+                               _name: &'static str,
+                               variant_index: u32,
+                               _variant: &'static str,
+                               _len: usize) -> SerializeResult<Self::SerializeStructVariant>
     {
-        value.serialize(self)
-    }
-
-    fn serialize_tuple_struct_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+        try!(self.add_enum_tag(variant_index));
+        Ok(Compound::new(self))
     }
 
-    fn serialize_tuple_variant(&mut self,
-                              _name: &'static str,
-                              variant_index: usize,
-                              _variant: &'static str,
-                              _len: usize) -> SerializeResult<()>
+    fn serialize_newtype_struct<T>(self,
+                               _name: &'static str,
+                               value: &T) -> SerializeResult<()>
+        where T: ?Sized + serde::ser::Serialize,
     {
-        self.add_enum_tag(variant_index)
+        value.serialize(self)
     }
 
-    fn serialize_tuple_variant_elt<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
+    fn serialize_newtype_variant<T>(self,
+                               _name: &'static str,
+                               variant_index: u32,
+                               _variant: &'static str,
+                               value: &T) -> SerializeResult<()>
+        where T: ?Sized + serde::ser::Serialize,
     {
+        try!(self.add_enum_tag(variant_index));
         value.serialize(self)
     }
 
-    fn serialize_tuple_variant_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_unit_variant(self,
+                          _name: &'static str,
+                          variant_index: u32,
+                          _variant: &'static str) -> SerializeResult<()> {
+        self.add_enum_tag(variant_index)
     }
+}
 
-    fn serialize_map(&mut self, len: Option<usize>) -> SerializeResult<()> {
-        let len = len.expect("do not know how to serialize a map with no length");
-        self.serialize_usize(len)
+impl<'b, W: Writer> SerializePart for Serializer<'b, W> {
+    fn serialize_part<V: ?Sized + serde::Serialize>(&mut self, value: &V, segment: Segment) -> SerializeResult<()> {
+        value.serialize(&mut *self).map_err(|err| err.locate(self.written, Some(segment)))
     }
+}
 
-    fn serialize_map_key<K>(&mut self, _: &mut (), key: K) -> SerializeResult<()>
-        where K: serde::Serialize,
-    {
-        key.serialize(self)
+impl<'b, W: Writer> Serializer<'b, W> {
+    /// Serializes a part into a buffer that is written after it, counting
+    /// the buffered bytes against the size limit.
+    fn serialize_buffered<V: ?Sized + serde::Serialize>(&self, buffer: &mut Vec<u8>, value: &V, segment: Segment) -> SerializeResult<()> {
+        let offset = self.written + buffer.len() as u64;
+        let mut serializer = Serializer::bounded(buffer, self.size_limit, self.config);
+        serializer.written = offset;
+        serializer.serialize_part(value, segment)
+    }
+
+    /// Like `serialize_buffered`, but the presence of an `Option` goes into
+    /// `bitmap` rather than a tag byte.
+    fn serialize_probed<V: ?Sized + serde::Serialize>(&self, buffer: &mut Vec<u8>, value: &V, segment: Segment, bitmap: &mut Bitmap) -> SerializeResult<()> {
+        let offset = self.written + buffer.len() as u64;
+        let mut serializer = Serializer::bounded(buffer, self.size_limit, self.config);
+        serializer.written = offset;
+        value.serialize(OptionProbe { ser: &mut serializer, bitmap: bitmap })
+            .map_err(|err| err.locate(serializer.written, Some(segment)))
     }
+}
 
-    fn serialize_map_value<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
-    }
+/// The state of a sequence or map that is being serialized.
+///
+/// The elements of a sequence without a known length are buffered until
+/// they have been counted. The entries of a map that is sorted are buffered
+/// until all of them are known.
+pub struct SeqCompound<'a, 'b: 'a, W: 'b> {
+    ser: &'a mut Serializer<'b, W>,
+    index: usize,
+    elements: Option<Vec<u8>>,
+    /// The encoded entries of a sorted map, with the length of their key.
+    entries: Option<Vec<(Vec<u8>, usize)>>,
+}
 
-    fn serialize_map_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+impl<'a, 'b, W: Writer> SeqCompound<'a, 'b, W> {
+    fn new(ser: &'a mut Serializer<'b, W>, len: Option<usize>) -> SerializeResult<SeqCompound<'a, 'b, W>> {
+        let elements = match len {
+            Some(len) => {
+                try!(ser.write_unsigned(len as u64));
+                None
+            }
+            None => Some(Vec::new()),
+        };
+        Ok(SeqCompound { ser: ser, index: 0, elements: elements, entries: None })
+    }
+
+    fn new_map(ser: &'a mut Serializer<'b, W>, len: Option<usize>) -> SerializeResult<SeqCompound<'a, 'b, W>> {
+        let sorted = ser.config.sort_maps || ser.config.canonical;
+        let mut map = try!(SeqCompound::new(ser, len));
+        if sorted {
+            map.entries = Some(Vec::new());
+        }
+        Ok(map)
     }
 
-    fn serialize_struct(&mut self, _name: &'static str, _len: usize) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_part<V: ?Sized + serde::Serialize>(&mut self, value: &V, segment: Segment) -> SerializeResult<()> {
+        match self.elements {
+            Some(ref mut elements) => self.ser.serialize_buffered(elements, value, segment),
+            None => self.ser.serialize_part(value, segment),
+        }
     }
 
-    fn serialize_struct_elt<V>(&mut self, _: &mut (), _key: &'static str, value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
+    fn end(mut self) -> SerializeResult<()> {
+        if let Some(mut entries) = self.entries.take() {
+            entries.sort_by(|&(ref a, a_len), &(ref b, b_len)| a[..a_len].cmp(&b[..b_len]));
+            for (entry, _) in entries {
+                match self.elements {
+                    Some(ref mut elements) => elements.extend_from_slice(&entry),
+                    None => try!(self.ser.write_bytes(&entry)),
+                }
+            }
+        }
+        match self.elements {
+            Some(elements) => {
+                try!(self.ser.write_unsigned(self.index as u64));
+                self.ser.write_bytes(&elements)
+            }
+            None => Ok(()),
+        }
     }
+}
 
-    fn serialize_struct_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
-    }
+impl<'a, 'b, W: Writer> serde::ser::SerializeSeq for SeqCompound<'a, 'b, W> {
+    type Ok = ();
+    type Error = SerializeError;
 
-    fn serialize_struct_variant(&mut self,
-                               _name: &'static str,
-                               variant_index: usize,
-                               _variant: &'static str,
-                               _len: usize) -> SerializeResult<()>
-    {
-        self.add_enum_tag(variant_index)
+    fn serialize_element<V: ?Sized + serde::Serialize>(&mut self, value: &V) -> SerializeResult<()> {
+        self.index += 1;
+        let index = self.index - 1;
+        self.serialize_part(value, Segment::Index(index))
     }
 
-    fn serialize_struct_variant_elt<V>(&mut self, _: &mut (), _key: &'static str, value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
+    fn end(self) -> SerializeResult<()> {
+        SeqCompound::end(self)
     }
+}
 
-    fn serialize_struct_variant_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+impl<'a, 'b, W: Writer> serde::ser::SerializeMap for SeqCompound<'a, 'b, W> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_key<K: ?Sized + serde::Serialize>(&mut self, key: &K) -> SerializeResult<()> {
+        let index = self.index;
+        match self.entries {
+            Some(ref mut entries) => {
+                let mut entry = Vec::new();
+                try!(self.ser.serialize_buffered(&mut entry, key, Segment::Index(index)));
+                let key_len = entry.len();
+                entries.push((entry, key_len));
+                Ok(())
+            }
+            None => self.serialize_part(key, Segment::Index(index)),
+        }
     }
 
-    fn serialize_newtype_struct<T>(&mut self,
-                               _name: &'static str,
-                               value: T) -> SerializeResult<()>
-        where T: serde::ser::Serialize,
-    {
-        value.serialize(self)
+    fn serialize_value<V: ?Sized + serde::Serialize>(&mut self, value: &V) -> SerializeResult<()> {
+        self.index += 1;
+        let index = self.index - 1;
+        match self.entries.as_mut().and_then(|entries| entries.last_mut()) {
+            Some(&mut (ref mut entry, _)) => self.ser.serialize_buffered(entry, value, Segment::Index(index)),
+            None => self.serialize_part(value, Segment::Index(index)),
+        }
     }
 
-    fn serialize_newtype_variant<T>(&mut self,
-                               _name: &'static str,
-                               variant_index: usize,
-                               _variant: &'static str,
-                               value: T) -> SerializeResult<()>
-        where T: serde::ser::Serialize,
-    {
-        try!(self.add_enum_tag(variant_index));
-        value.serialize(self)
+    fn end(self) -> SerializeResult<()> {
+        SeqCompound::end(self)
     }
+}
 
-    fn serialize_unit_variant(&mut self,
-                          _name: &'static str,
-                          variant_index: usize,
-                          _variant: &'static str) -> SerializeResult<()> {
-        self.add_enum_tag(variant_index)
+/// The state of a struct that is being serialized.
+///
+/// The fields of a length prefixed struct are buffered until its length is known,
+/// those of a struct with a presence bitmap until the bitmap is complete.
+pub struct StructCompound<'a, 'b: 'a, W: 'b> {
+    ser: &'a mut Serializer<'b, W>,
+    name: &'static str,
+    fields: Option<Vec<u8>>,
+    bitmap: Option<Bitmap>,
+}
+
+impl<'a, 'b, W: Writer> serde::ser::SerializeStruct for StructCompound<'a, 'b, W> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<V>(&mut self, key: &'static str, value: &V) -> SerializeResult<()>
+        where V: ?Sized + serde::Serialize,
+    {
+        let result = match (&mut self.fields, &mut self.bitmap) {
+            (&mut Some(ref mut fields), &mut Some(ref mut bitmap)) =>
+                self.ser.serialize_probed(fields, value, Segment::Field(key), bitmap),
+            (&mut Some(ref mut fields), &mut None) => self.ser.serialize_buffered(fields, value, Segment::Field(key)),
+            (&mut None, _) => self.ser.serialize_part(value, Segment::Field(key)),
+        };
+        result.map_err(|err| err.locate(self.ser.written, Some(Segment::Struct(self.name))))
+    }
+
+    fn end(self) -> SerializeResult<()> {
+        match (self.fields, self.bitmap) {
+            (Some(fields), Some(bitmap)) => {
+                try!(self.ser.write_bytes(&bitmap.encode()));
+                self.ser.write_bytes(&fields)
+            }
+            (Some(fields), None) => {
+                try!(self.ser.write_unsigned(fields.len() as u64));
+                self.ser.write_bytes(&fields)
+            }
+            (None, _) => Ok(()),
+        }
     }
 }
 
+/// A Serializer that only counts the bytes a value would be serialized to.
+///
+/// No bytes are encoded, so this is a cheap way to learn the serialized size
+/// of a value, see also `serialized_size`.
 pub struct SizeChecker {
     pub size_limit: u64,
     pub written: u64,
+    struct_enc: StructEncoding,
     float_size_f32: usize,
     float_size_f64: usize,
+    array_len_prefix: bool,
 }
 
 impl SizeChecker {
-    pub fn new(limit: u64, float_enc: FloatEncoding) -> SizeChecker {
-        let (float_size_f32, float_size_f64) = float_sizes(float_enc);
+    pub fn new<C: Into<Config>>(limit: u64, config: C) -> SizeChecker {
+        let config = config.into();
+        let (float_size_f32, float_size_f64) = float_sizes(config.float_enc);
         SizeChecker {
             size_limit: limit,
             written: 0,
+            struct_enc: config.struct_enc,
             float_size_f32: float_size_f32,
             float_size_f64: float_size_f64,
+            array_len_prefix: config.array_len_prefix,
         }
     }
 
     fn add_raw(&mut self, size: usize) -> SerializeResult<()> {
-        self.written += size as u64;
-        if self.written <= self.size_limit {
+        // Only count the value if it fits, so that errors point at its start.
+        if self.written + size as u64 <= self.size_limit {
+            self.written += size as u64;
             Ok(())
         } else {
             Err(SerializeError::SizeLimit)
@@ -376,264 +621,363 @@ impl SizeChecker {
     }*/
 
     fn add_value_unsigned<T: Into<u64>>(&mut self, t: T) -> SerializeResult<()> {
-        let mut v: Vec<u8> = vec![];
-        match leb128::write::unsigned(&mut v, t.into()) {
-            Ok(n) => self.add_raw(n),
-            Err(e) => Err(wrap_io(e))
-        }
+        self.add_raw(varint::unsigned_size(t.into()))
     }
 
     fn add_value_signed<T: Into<i64>>(&mut self, t: T) -> SerializeResult<()> {
-        let mut v: Vec<u8> = vec![];
-        match leb128::write::signed(&mut v, t.into()) {
-            Ok(n) => self.add_raw(n),
-            Err(e) => Err(wrap_io(e))
-        }
+        self.add_raw(varint::signed_size(t.into()))
     }
 
-    fn add_enum_tag(&mut self, tag: usize) -> SerializeResult<()> {
-        self.add_value_unsigned(tag as u64)
+    fn add_enum_tag(&mut self, tag: u32) -> SerializeResult<()> {
+        self.add_value_unsigned(tag)
     }
 }
 
-impl serde::Serializer for SizeChecker {
+impl<'a> serde::Serializer for &'a mut SizeChecker {
+    type Ok = ();
     type Error = SerializeError;
-    type SeqState = ();
-    type TupleState = ();
-    type TupleStructState = ();
-    type TupleVariantState = ();
-    type MapState = ();
-    type StructState = ();
-    type StructVariantState = ();
+    type SerializeSeq = SeqSizeCompound<'a>;
+    type SerializeTuple = Compound<'a, SizeChecker>;
+    type SerializeTupleStruct = Compound<'a, SizeChecker>;
+    type SerializeTupleVariant = Compound<'a, SizeChecker>;
+    type SerializeMap = SeqSizeCompound<'a>;
+    type SerializeStruct = StructSizeCompound<'a>;
+    type SerializeStructVariant = Compound<'a, SizeChecker>;
+
+    fn is_human_readable(&self) -> bool { false }
 
-    fn serialize_unit(&mut self) -> SerializeResult<()> { Ok(()) }
+    fn serialize_unit(self) -> SerializeResult<()> { Ok(()) }
 
-    fn serialize_unit_struct(&mut self, _: &'static str) -> SerializeResult<()> { Ok(()) }
+    fn serialize_unit_struct(self, _: &'static str) -> SerializeResult<()> { Ok(()) }
 
-    fn serialize_bool(&mut self, _: bool) -> SerializeResult<()> {
-        self.add_value_unsigned(0 as u8)
+    fn serialize_bool(self, _: bool) -> SerializeResult<()> {
+        self.add_raw(1)
     }
 
-    fn serialize_u8(&mut self, _: u8) -> SerializeResult<()> {
-        self.add_value_unsigned(0 as u8)
+    fn serialize_u8(self, _: u8) -> SerializeResult<()> {
+        self.add_raw(1)
     }
 
-    fn serialize_u16(&mut self, v: u16) -> SerializeResult<()> {
+    fn serialize_u16(self, v: u16) -> SerializeResult<()> {
         self.add_value_unsigned(v)
     }
 
-    fn serialize_u32(&mut self, v: u32) -> SerializeResult<()> {
+    fn serialize_u32(self, v: u32) -> SerializeResult<()> {
         self.add_value_unsigned(v)
     }
 
-    fn serialize_u64(&mut self, v: u64) -> SerializeResult<()> {
+    fn serialize_u64(self, v: u64) -> SerializeResult<()> {
         self.add_value_unsigned(v)
     }
 
-    fn serialize_usize(&mut self, v: usize) -> SerializeResult<()> {
-        self.add_value_unsigned(v as u64)
+    fn serialize_u128(self, v: u128) -> SerializeResult<()> {
+        self.add_raw(varint::unsigned_size128(v))
     }
 
-    fn serialize_i8(&mut self, v: i8) -> SerializeResult<()> {
-        self.add_value_signed(v)
+    fn serialize_i8(self, _: i8) -> SerializeResult<()> {
+        // Written as a single byte, not as a varint.
+        self.add_raw(1)
     }
 
-    fn serialize_i16(&mut self, v: i16) -> SerializeResult<()> {
+    fn serialize_i16(self, v: i16) -> SerializeResult<()> {
         self.add_value_signed(v)
     }
 
-    fn serialize_i32(&mut self, v: i32) -> SerializeResult<()> {
+    fn serialize_i32(self, v: i32) -> SerializeResult<()> {
         self.add_value_signed(v)
     }
 
-    fn serialize_i64(&mut self, v: i64) -> SerializeResult<()> {
+    fn serialize_i64(self, v: i64) -> SerializeResult<()> {
         self.add_value_signed(v)
     }
 
-    fn serialize_isize(&mut self, v: isize) -> SerializeResult<()> {
-        self.serialize_i64(v as i64)
+    fn serialize_i128(self, v: i128) -> SerializeResult<()> {
+        self.add_raw(varint::signed_size128(v))
     }
 
-    fn serialize_f32(&mut self, _: f32) -> SerializeResult<()> {
+    fn serialize_f32(self, _: f32) -> SerializeResult<()> {
         let bytes = self.float_size_f32;
         self.add_raw(bytes)
     }
 
-    fn serialize_f64(&mut self, _: f64) -> SerializeResult<()> {
+    fn serialize_f64(self, _: f64) -> SerializeResult<()> {
         let bytes = self.float_size_f64;
         self.add_raw(bytes)
     }
 
-    fn serialize_str(&mut self, v: &str) -> SerializeResult<()> {
+    fn serialize_str(self, v: &str) -> SerializeResult<()> {
         self.add_value_unsigned(v.len() as u64)?;
         self.add_raw(v.len())
     }
 
-    fn serialize_char(&mut self, c: char) -> SerializeResult<()> {
+    fn serialize_char(self, c: char) -> SerializeResult<()> {
         self.add_raw(encode_utf8(c).as_slice().len())
     }
 
-    fn serialize_bytes(&mut self, v: &[u8]) -> SerializeResult<()> {
-        let mut state = try!(self.serialize_seq(Some(v.len())));
-        for c in v {
-            try!(self.serialize_seq_elt(&mut state, c));
-        }
-        self.serialize_seq_end(state)
+    fn serialize_bytes(self, v: &[u8]) -> SerializeResult<()> {
+        self.add_value_unsigned(v.len() as u64)?;
+        self.add_raw(v.len())
     }
 
-    fn serialize_none(&mut self) -> SerializeResult<()> {
-        self.add_value_unsigned(0 as u8)
+    fn serialize_none(self) -> SerializeResult<()> {
+        self.add_raw(1)
     }
 
-    fn serialize_some<T>(&mut self, v: T) -> SerializeResult<()>
-        where T: serde::Serialize,
+    fn serialize_some<T>(self, v: &T) -> SerializeResult<()>
+        where T: ?Sized + serde::Serialize,
     {
-        self.add_value_unsigned(1 as u8)?;
+        self.add_raw(1)?;
         v.serialize(self)
     }
 
-    fn serialize_seq(&mut self, len: Option<usize>) -> SerializeResult<()> {
-        let len = len.expect("do not know how to serialize a sequence with no length");
+    fn serialize_seq(self, len: Option<usize>) -> SerializeResult<Self::SerializeSeq> {
+        SeqSizeCompound::new(self, len)
+    }
 
-        self.serialize_usize(len)
+    fn serialize_tuple(self, len: usize) -> SerializeResult<Self::SerializeTuple> {
+        if self.array_len_prefix {
+            self.add_value_unsigned(len as u64)?;
+        }
+        Ok(Compound::new(self))
     }
 
-    fn serialize_seq_elt<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> SerializeResult<Self::SerializeTupleStruct> {
+        Ok(Compound::new(self))
     }
 
-    fn serialize_seq_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_tuple_variant(self,
+                         _name: &'static str,
+                         variant_index: u32,
+                         _variant: &'static str,
+                         _len: usize) -> SerializeResult<Self::SerializeTupleVariant>
+    {
+        try!(self.add_enum_tag(variant_index));
+        Ok(Compound::new(self))
     }
 
-    fn serialize_seq_fixed_size(&mut self, len: usize) -> SerializeResult<()> {
-        self.serialize_seq(Some(len))
+    fn serialize_map(self, len: Option<usize>) -> SerializeResult<Self::SerializeMap> {
+        SeqSizeCompound::new(self, len)
     }
 
-    fn serialize_tuple(&mut self, _len: usize) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_struct(self, name: &'static str, _len: usize) -> SerializeResult<Self::SerializeStruct> {
+        let (start, bitmap) = match self.struct_enc {
+            StructEncoding::Tuple => (None, None),
+            StructEncoding::LengthPrefixed => (Some(self.written), None),
+            StructEncoding::PresenceBitmap => (None, Some(Bitmap::default())),
+        };
+        Ok(StructSizeCompound { checker: self, name: name, start: start, bitmap: bitmap })
     }
 
-    fn serialize_tuple_elt<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
+    fn serialize_struct_variant(self,
+                               _name: &'static str,
+                               variant_index: u32,
+                               _variant: &'static str,
+                               _len: usize) -> SerializeResult<Self::SerializeStructVariant>
     {
-        value.serialize(self)
+        try!(self.add_enum_tag(variant_index));
+        Ok(Compound::new(self))
     }
 
-    fn serialize_tuple_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_newtype_struct<V: ?Sized + serde::Serialize>(self, _name: &'static str, v: &V) -> SerializeResult<()> {
+        v.serialize(self)
     }
 
-    fn serialize_tuple_struct(&mut self, _name: &'static str, _len: usize) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_unit_variant(self,
+                          _name: &'static str,
+                          variant_index: u32,
+                          _variant: &'static str) -> SerializeResult<()> {
+        self.add_enum_tag(variant_index)
     }
 
-    fn serialize_tuple_struct_elt<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
+    fn serialize_newtype_variant<V: ?Sized + serde::Serialize>(self,
+                               _name: &'static str,
+                               variant_index: u32,
+                               _variant: &'static str,
+                               value: &V) -> SerializeResult<()>
     {
+        try!(self.add_enum_tag(variant_index));
         value.serialize(self)
     }
+}
 
-    fn serialize_tuple_struct_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+impl SerializePart for SizeChecker {
+    fn serialize_part<V: ?Sized + serde::Serialize>(&mut self, value: &V, segment: Segment) -> SerializeResult<()> {
+        value.serialize(&mut *self).map_err(|err| err.locate(self.written, Some(segment)))
     }
+}
 
-    fn serialize_tuple_variant(&mut self,
-                         _name: &'static str,
-                         variant_index: usize,
-                         _variant: &'static str,
-                         _len: usize) -> SerializeResult<()>
+/// The state of a struct whose size is being checked.
+///
+/// `start` is the number of bytes written before the fields of a length prefixed struct.
+pub struct StructSizeCompound<'a> {
+    checker: &'a mut SizeChecker,
+    name: &'static str,
+    start: Option<u64>,
+    bitmap: Option<Bitmap>,
+}
+
+impl<'a> serde::ser::SerializeStruct for StructSizeCompound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<V>(&mut self, key: &'static str, value: &V) -> SerializeResult<()>
+        where V: ?Sized + serde::Serialize,
     {
-        self.add_enum_tag(variant_index)
+        let name = self.name;
+        let checker = &mut *self.checker;
+        let result = match self.bitmap {
+            Some(ref mut bitmap) => value.serialize(OptionProbe { ser: &mut *checker, bitmap: bitmap })
+                .map_err(|err| err.locate(checker.written, Some(Segment::Field(key)))),
+            None => checker.serialize_part(value, Segment::Field(key)),
+        };
+        result.map_err(|err| err.locate(checker.written, Some(Segment::Struct(name))))
+    }
+
+    fn end(self) -> SerializeResult<()> {
+        if let Some(bitmap) = self.bitmap {
+            return self.checker.add_raw(bitmap.encoded_len());
+        }
+        match self.start {
+            Some(start) => {
+                let len = self.checker.written - start;
+                self.checker.add_value_unsigned(len)
+            }
+            None => Ok(()),
+        }
     }
+}
 
-    fn serialize_tuple_variant_elt<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
+/// The state of a sequence or map whose size is being checked.
+///
+/// The length of a sequence without a known length is added once its elements
+/// have been counted.
+pub struct SeqSizeCompound<'a> {
+    checker: &'a mut SizeChecker,
+    index: usize,
+    counted: bool,
+}
+
+impl<'a> SeqSizeCompound<'a> {
+    fn new(checker: &'a mut SizeChecker, len: Option<usize>) -> SerializeResult<SeqSizeCompound<'a>> {
+        if let Some(len) = len {
+            try!(checker.add_value_unsigned(len as u64));
+        }
+        Ok(SeqSizeCompound { checker: checker, index: 0, counted: len.is_none() })
     }
 
-    fn serialize_tuple_variant_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+    fn end(self) -> SerializeResult<()> {
+        if self.counted {
+            self.checker.add_value_unsigned(self.index as u64)
+        } else {
+            Ok(())
+        }
     }
+}
 
-    fn serialize_map(&mut self, len: Option<usize>) -> SerializeResult<()>
-    {
-        let len = len.expect("do not know how to serialize a map with no length");
+impl<'a> serde::ser::SerializeSeq for SeqSizeCompound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
 
-        self.serialize_usize(len)
+    fn serialize_element<V: ?Sized + serde::Serialize>(&mut self, value: &V) -> SerializeResult<()> {
+        self.index += 1;
+        self.checker.serialize_part(value, Segment::Index(self.index - 1))
     }
 
-    fn serialize_map_key<K>(&mut self, _: &mut (), key: K) -> SerializeResult<()>
-        where K: serde::Serialize,
-    {
-        key.serialize(self)
+    fn end(self) -> SerializeResult<()> {
+        SeqSizeCompound::end(self)
     }
+}
 
-    fn serialize_map_value<V>(&mut self, _: &mut (), value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
+impl<'a> serde::ser::SerializeMap for SeqSizeCompound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_key<K: ?Sized + serde::Serialize>(&mut self, key: &K) -> SerializeResult<()> {
+        self.checker.serialize_part(key, Segment::Index(self.index))
     }
 
-    fn serialize_map_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_value<V: ?Sized + serde::Serialize>(&mut self, value: &V) -> SerializeResult<()> {
+        self.index += 1;
+        self.checker.serialize_part(value, Segment::Index(self.index - 1))
     }
 
-    fn serialize_struct(&mut self, _name: &'static str, _len: usize) -> SerializeResult<()> {
-        Ok(())
+    fn end(self) -> SerializeResult<()> {
+        SeqSizeCompound::end(self)
     }
+}
 
-    fn serialize_struct_elt<V>(&mut self, _: &mut (), _key: &'static str, value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
+/// Serializers that add the segment of each part of a compound value to the
+/// path of its errors.
+pub trait SerializePart {
+    fn serialize_part<V: ?Sized + serde::Serialize>(&mut self, value: &V, segment: Segment) -> SerializeResult<()>;
+}
+
+/// The state of a compound value, its elements are counted for error positions.
+pub struct Compound<'a, S: 'a> {
+    ser: &'a mut S,
+    index: usize,
+}
+
+impl<'a, S: SerializePart> Compound<'a, S> {
+    fn new(ser: &'a mut S) -> Compound<'a, S> {
+        Compound { ser: ser, index: 0 }
     }
 
-    fn serialize_struct_end(&mut self, _: ()) -> SerializeResult<()> {
-        Ok(())
+    fn serialize_next<V: ?Sized + serde::Serialize>(&mut self, value: &V, segment: fn(usize) -> Segment<'static>) -> SerializeResult<()> {
+        self.index += 1;
+        self.ser.serialize_part(value, segment(self.index - 1))
     }
+}
 
-    fn serialize_struct_variant(&mut self,
-                               _name: &'static str,
-                               variant_index: usize,
-                               _variant: &'static str,
-                               _len: usize) -> SerializeResult<()>
-    {
-        self.add_enum_tag(variant_index)
+impl<'a, S: SerializePart> serde::ser::SerializeTuple for Compound<'a, S> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_element<V: ?Sized + serde::Serialize>(&mut self, value: &V) -> SerializeResult<()> {
+        self.serialize_next(value, Segment::Element)
     }
 
-    fn serialize_struct_variant_elt<V>(&mut self, _: &mut (), _field: &'static str, value: V) -> SerializeResult<()>
-        where V: serde::Serialize,
-    {
-        value.serialize(self)
+    fn end(self) -> SerializeResult<()> {
+        Ok(())
     }
+}
+
+impl<'a, S: SerializePart> serde::ser::SerializeTupleStruct for Compound<'a, S> {
+    type Ok = ();
+    type Error = SerializeError;
 
-    fn serialize_struct_variant_end(&mut self, _: ()) -> SerializeResult<()> {
+    fn serialize_field<V: ?Sized + serde::Serialize>(&mut self, value: &V) -> SerializeResult<()> {
+        self.serialize_next(value, Segment::Element)
+    }
+
+    fn end(self) -> SerializeResult<()> {
         Ok(())
     }
+}
 
-    fn serialize_newtype_struct<V: serde::Serialize>(&mut self, _name: &'static str, v: V) -> SerializeResult<()> {
-        v.serialize(self)
+impl<'a, S: SerializePart> serde::ser::SerializeTupleVariant for Compound<'a, S> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<V: ?Sized + serde::Serialize>(&mut self, value: &V) -> SerializeResult<()> {
+        self.serialize_next(value, Segment::Element)
     }
 
-    fn serialize_unit_variant(&mut self,
-                          _name: &'static str,
-                          variant_index: usize,
-                          _variant: &'static str) -> SerializeResult<()> {
-        self.add_enum_tag(variant_index)
+    fn end(self) -> SerializeResult<()> {
+        Ok(())
     }
+}
 
-    fn serialize_newtype_variant<V: serde::Serialize>(&mut self,
-                               _name: &'static str,
-                               variant_index: usize,
-                               _variant: &'static str,
-                               value: V) -> SerializeResult<()>
-    {
-        try!(self.add_enum_tag(variant_index));
-        value.serialize(self)
+impl<'a, S: SerializePart> serde::ser::SerializeStructVariant for Compound<'a, S> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<V: ?Sized + serde::Serialize>(&mut self, key: &'static str, value: &V) -> SerializeResult<()> {
+        self.ser.serialize_part(value, Segment::Field(key))
+    }
+
+    fn end(self) -> SerializeResult<()> {
+        Ok(())
     }
 }
 
@@ -0,0 +1,215 @@
+//! LEB128 encoding of integers.
+
+use io::{self, IoError, Reader, Writer};
+
+/// Returns the number of bytes `v` takes as unsigned LEB128.
+#[inline]
+pub fn unsigned_size(v: u64) -> usize {
+    // Every byte holds 7 bits, zero still takes one byte.
+    let bits = 64 - (v | 1).leading_zeros() as usize;
+    bits.div_ceil(7)
+}
+
+/// Returns the number of bytes `v` takes as signed LEB128.
+#[inline]
+pub fn signed_size(v: i64) -> usize {
+    // The sign bit has to fit into the last byte as well.
+    let magnitude = if v < 0 { !v } else { v };
+    let bits = 64 - magnitude.leading_zeros() as usize + 1;
+    bits.div_ceil(7)
+}
+
+/// Returns the number of bytes `v` takes as unsigned LEB128.
+#[inline]
+pub fn unsigned_size128(v: u128) -> usize {
+    let bits = 128 - (v | 1).leading_zeros() as usize;
+    bits.div_ceil(7)
+}
+
+/// Returns the number of bytes `v` takes as signed LEB128.
+#[inline]
+pub fn signed_size128(v: i128) -> usize {
+    let magnitude = if v < 0 { !v } else { v };
+    let bits = 128 - magnitude.leading_zeros() as usize + 1;
+    bits.div_ceil(7)
+}
+
+/// An error while reading a LEB128 encoded integer.
+#[derive(Debug)]
+pub enum ReadError {
+    IoError(IoError),
+    /// The integer does not fit into its type, 64 or 128 bits.
+    Overflow,
+}
+
+impl From<IoError> for ReadError {
+    fn from(err: IoError) -> ReadError {
+        ReadError::IoError(err)
+    }
+}
+
+/// Writes `v` as unsigned LEB128 and returns the number of bytes written.
+pub fn write_unsigned<W: Writer + ?Sized>(writer: &mut W, mut v: u64) -> Result<usize, IoError> {
+    let mut buf = [0; 10];
+    let mut n = 0;
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf[n] = byte;
+            n += 1;
+            break;
+        }
+        buf[n] = byte | 0x80;
+        n += 1;
+    }
+    try!(writer.write_all(&buf[..n]));
+    Ok(n)
+}
+
+/// Writes `v` as signed LEB128 and returns the number of bytes written.
+pub fn write_signed<W: Writer + ?Sized>(writer: &mut W, mut v: i64) -> Result<usize, IoError> {
+    let mut buf = [0; 10];
+    let mut n = 0;
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        // Done once the remaining bits are all copies of the sign bit of `byte`.
+        let done = (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0);
+        if done {
+            buf[n] = byte;
+            n += 1;
+            break;
+        }
+        buf[n] = byte | 0x80;
+        n += 1;
+    }
+    try!(writer.write_all(&buf[..n]));
+    Ok(n)
+}
+
+/// Reads an unsigned LEB128 integer, returns it and the number of bytes read.
+pub fn read_unsigned<R: Reader + ?Sized>(reader: &mut R) -> Result<(u64, usize), ReadError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut n = 0;
+    loop {
+        let byte = try!(io::read_u8(reader));
+        n += 1;
+        // The tenth byte only has room for the highest bit.
+        if shift == 63 && byte != 0x00 && byte != 0x01 {
+            return Err(ReadError::Overflow);
+        }
+        result |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((result, n));
+        }
+        shift += 7;
+    }
+}
+
+/// Reads a signed LEB128 integer, returns it and the number of bytes read.
+pub fn read_signed<R: Reader + ?Sized>(reader: &mut R) -> Result<(i64, usize), ReadError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut n = 0;
+    loop {
+        let byte = try!(io::read_u8(reader));
+        n += 1;
+        // The tenth byte only has room for the sign bit.
+        if shift == 63 && byte != 0x00 && byte != 0x7f {
+            return Err(ReadError::Overflow);
+        }
+        result |= ((byte & 0x7f) as i64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            // Sign extend negative numbers.
+            if shift < 64 && byte & 0x40 != 0 {
+                result |= !0 << shift;
+            }
+            return Ok((result, n));
+        }
+    }
+}
+
+/// Writes `v` as unsigned LEB128 and returns the number of bytes written.
+pub fn write_unsigned128<W: Writer + ?Sized>(writer: &mut W, mut v: u128) -> Result<usize, IoError> {
+    let mut buf = [0; 19];
+    let mut n = 0;
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf[n] = byte;
+            n += 1;
+            break;
+        }
+        buf[n] = byte | 0x80;
+        n += 1;
+    }
+    try!(writer.write_all(&buf[..n]));
+    Ok(n)
+}
+
+/// Writes `v` as signed LEB128 and returns the number of bytes written.
+pub fn write_signed128<W: Writer + ?Sized>(writer: &mut W, mut v: i128) -> Result<usize, IoError> {
+    let mut buf = [0; 19];
+    let mut n = 0;
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        let done = (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0);
+        if done {
+            buf[n] = byte;
+            n += 1;
+            break;
+        }
+        buf[n] = byte | 0x80;
+        n += 1;
+    }
+    try!(writer.write_all(&buf[..n]));
+    Ok(n)
+}
+
+/// Reads an unsigned LEB128 integer, returns it and the number of bytes read.
+pub fn read_unsigned128<R: Reader + ?Sized>(reader: &mut R) -> Result<(u128, usize), ReadError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut n = 0;
+    loop {
+        let byte = try!(io::read_u8(reader));
+        n += 1;
+        // The nineteenth byte only has room for the two highest bits.
+        if shift == 126 && byte > 0x03 {
+            return Err(ReadError::Overflow);
+        }
+        result |= ((byte & 0x7f) as u128) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((result, n));
+        }
+        shift += 7;
+    }
+}
+
+/// Reads a signed LEB128 integer, returns it and the number of bytes read.
+pub fn read_signed128<R: Reader + ?Sized>(reader: &mut R) -> Result<(i128, usize), ReadError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut n = 0;
+    loop {
+        let byte = try!(io::read_u8(reader));
+        n += 1;
+        // The nineteenth byte only has room for the highest bit and the sign bit.
+        if shift == 126 && byte != 0x00 && byte != 0x01 && byte != 0x7e && byte != 0x7f {
+            return Err(ReadError::Overflow);
+        }
+        result |= ((byte & 0x7f) as i128) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 128 && byte & 0x40 != 0 {
+                result |= !0 << shift;
+            }
+            return Ok((result, n));
+        }
+    }
+}
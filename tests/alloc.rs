@@ -0,0 +1,102 @@
+//! Checks that a steady stream of messages and computing sizes do not allocate.
+//!
+//! This is a separate test binary because it installs a counting allocator.
+
+extern crate mincode;
+#[macro_use] extern crate serde_derive;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use mincode::{Encoder, Decoder, FloatEncoding};
+use mincode::SizeLimit::Infinite;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// The tests run in parallel, but only one at a time may count allocations.
+static COUNTING: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Entity {
+    name: String,
+    position: (f32, f32),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Packet {
+    tick: u64,
+    entities: Vec<Entity>,
+}
+
+#[test]
+fn steady_state_does_not_allocate() {
+    let _counting = COUNTING.lock().unwrap_or_else(|err| err.into_inner());
+    let mut encoder = Encoder::new(Infinite, FloatEncoding::Normal);
+    let mut decoder = Decoder::new(Infinite, FloatEncoding::Normal);
+    let mut packet = Packet {
+        tick: 0,
+        entities: vec![
+            Entity { name: "player".to_string(), position: (0.0, 0.0) },
+            Entity { name: "camera".to_string(), position: (0.0, 1.0) },
+        ],
+    };
+    let mut received = Packet { tick: 0, entities: Vec::new() };
+
+    for tick in 0..10 {
+        let before = ALLOCATIONS.load(Ordering::SeqCst);
+        packet.tick = tick;
+        packet.entities[0].position.0 += 1.0;
+        {
+            let bytes = encoder.encode(&packet).unwrap();
+            decoder.decode_in_place(&mut received, bytes).unwrap();
+        }
+        let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+        // The first packet sizes the buffers.
+        if tick > 0 {
+            assert_eq!(allocations, 0, "tick {} allocated", tick);
+        }
+        assert_eq!(received, packet);
+    }
+}
+
+#[test]
+fn sizes_do_not_allocate() {
+    let _counting = COUNTING.lock().unwrap_or_else(|err| err.into_inner());
+    let packet = Packet {
+        tick: 1 << 40,
+        entities: (0..1000).map(|i| Entity { name: format!("entity {}", i), position: (i as f32, 0.0) }).collect(),
+    };
+    let pairs: Vec<(u64, String)> = (0..1000).map(|i| (i * 7919, format!("entity {}", i))).collect();
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    let size = mincode::serde::serialized_size(&packet, FloatEncoding::Normal);
+    let bounded = mincode::serde::serialized_size_bounded(&packet, size, FloatEncoding::Normal);
+    let encoded_size = mincode::rustc_serialize::encoded_size(&pairs, FloatEncoding::Normal);
+    assert_eq!(ALLOCATIONS.load(Ordering::SeqCst) - before, 0);
+
+    assert_eq!(bounded, Some(size));
+    assert_eq!(size, mincode::serde::serialize(&packet, Infinite, FloatEncoding::Normal).unwrap().len() as u64);
+    assert_eq!(encoded_size, mincode::rustc_serialize::encode(&pairs, Infinite, FloatEncoding::Normal).unwrap().len() as u64);
+}
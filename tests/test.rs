@@ -1,4 +1,4 @@
-#![feature(proc_macro, step_by)]
+#![feature(step_by)]
 
 extern crate mincode;
 extern crate rustc_serialize;
@@ -11,14 +11,14 @@ use std::ops::Deref;
 
 use rustc_serialize::{Encodable, Decodable};
 
-use mincode::{RefBox, StrBox, SliceBox, BVec, BitVec, FloatEncoding/*, BPack*/};
+use mincode::{RefBox, StrBox, SliceBox, BVec, BitVec, FloatEncoding, Config, StructEncoding/*, BPack*/};
 
 use mincode::SizeLimit::{self, Infinite, Bounded};
 use mincode::rustc_serialize::{encode, decode, decode_from, DecodingError};
 use mincode::serde::{serialize, deserialize, deserialize_from, DeserializeError, DeserializeResult};
 
 fn proxy_encode<V>(element: &V, size_limit: SizeLimit, float_enc: FloatEncoding) -> Vec<u8>
-    where V: Encodable + Decodable + serde::Serialize + serde::Deserialize + PartialEq + Debug + 'static
+    where V: Encodable + Decodable + serde::Serialize + serde::de::DeserializeOwned + PartialEq + Debug + 'static
 {
     let v1 = mincode::rustc_serialize::encode(element, size_limit, float_enc).unwrap();
     let v2 = mincode::serde::serialize(element, size_limit, float_enc).unwrap();
@@ -28,7 +28,7 @@ fn proxy_encode<V>(element: &V, size_limit: SizeLimit, float_enc: FloatEncoding)
 }
 
 fn proxy_decode<V>(slice: &[u8], float_enc: FloatEncoding) -> V
-    where V: Encodable + Decodable + serde::Serialize + serde::Deserialize + PartialEq + Debug + 'static
+    where V: Encodable + Decodable + serde::Serialize + serde::de::DeserializeOwned + PartialEq + Debug + 'static
 {
     let e1 = mincode::rustc_serialize::decode(slice, float_enc).unwrap();
     let e2 = mincode::serde::deserialize(slice, float_enc).unwrap();
@@ -47,8 +47,12 @@ fn proxy_encoded_size<V>(element: &V, float_enc: FloatEncoding) -> u64
     ser_size
 }
 
+fn is_non_canonical<T: Debug>(res: DeserializeResult<T>) -> bool {
+    matches!(res.map_err(DeserializeError::into_inner), Err(DeserializeError::NonCanonical(_)))
+}
+
 fn the_same<V>(element: V, float_enc: FloatEncoding)
-    where V: Encodable+Decodable+serde::Serialize+serde::Deserialize+PartialEq+Debug+'static
+    where V: Encodable+Decodable+serde::Serialize+serde::de::DeserializeOwned+PartialEq+Debug+'static
 {
     // Make sure that the bahavior isize correct when wrapping with a RefBox.
     fn ref_box_correct<V>(v: &V, float_enc: FloatEncoding) -> bool
@@ -145,6 +149,315 @@ fn test_nested_struct() {
     }, FloatEncoding::Normal);
 }
 
+#[test]
+fn test_length_prefixed_struct() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Old {
+        x: u32,
+        s: String,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct New {
+        x: u32,
+        s: String,
+        #[serde(default)]
+        y: Vec<u8>,
+    }
+    let config = Config { struct_enc: StructEncoding::LengthPrefixed, ..Config::default() };
+
+    let old = Old { x: 5, s: "foo".to_string() };
+    let new = New { x: 5, s: "foo".to_string(), y: vec![1, 2, 3] };
+    let old_encoded = serialize(&old, Infinite, config).unwrap();
+    let new_encoded = serialize(&new, Infinite, config).unwrap();
+    // 1 byte for the struct length.
+    assert_eq!(old_encoded.len(), 1 + 1 + 1 + 3);
+    assert_eq!(mincode::serde::serialized_size(&new, config), new_encoded.len() as u64);
+
+    // A newer reader defaults the missing trailing fields.
+    let decoded: New = deserialize(&old_encoded, config).unwrap();
+    assert_eq!(decoded, New { x: 5, s: "foo".to_string(), y: vec![] });
+
+    // An older reader skips the unknown trailing fields.
+    let both = [&new_encoded[..], &old_encoded[..]].concat();
+    let mut reader = &both[..];
+    let decoded: Old = deserialize_from(&mut reader, Infinite, config).unwrap();
+    assert_eq!(decoded, old);
+    let decoded: Old = deserialize_from(&mut reader, Infinite, config).unwrap();
+    assert_eq!(decoded, old);
+    assert!(reader.is_empty());
+
+    // Fields overrunning the length prefix are an invalid encoding.
+    match deserialize::<Old, _>(&[2, 5, 3, b'f', b'o', b'o'], config).map_err(DeserializeError::into_inner) {
+        Err(DeserializeError::InvalidEncoding(_)) => {},
+        res => panic!("Expecting InvalidEncoding, got {:?}", res),
+    }
+}
+
+#[test]
+fn test_canonical() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Old {
+        x: u32,
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct New {
+        x: u32,
+        #[serde(default)]
+        y: u32,
+    }
+
+    let canonical = Config { canonical: true, ..Config::default() };
+
+    // Overlong integers and lengths decode, just not in canonical mode.
+    type Decode = fn(&[u8], Config) -> DeserializeResult<()>;
+    let overlong: &[(&[u8], Decode)] = &[
+        (&[0x80, 0x00], |b, c| deserialize::<u32, _>(b, c).map(|_| ())),
+        (&[0xff, 0x7f], |b, c| deserialize::<i32, _>(b, c).map(|_| ())),
+        (&[0x80, 0x80, 0x00], |b, c| deserialize::<u64, _>(b, c).map(|_| ())),
+        (&[0x83, 0x00, b'a', b'b', b'c'], |b, c| deserialize::<String, _>(b, c).map(|_| ())),
+    ];
+    for &(bytes, decode) in overlong {
+        assert!(decode(bytes, Config::default()).is_ok());
+        assert!(is_non_canonical(decode(bytes, canonical)));
+    }
+    // Minimal ones are fine, including the ten byte `u64::MAX`.
+    let value = (0u32, -1i32, 300u64, u64::MAX, i64::MIN, "abc".to_string());
+    let encoded = serialize(&value, Infinite, canonical).unwrap();
+    assert_eq!(deserialize::<(u32, i32, u64, u64, i64, String), _>(&encoded, canonical).unwrap(), value);
+
+    // Every NaN is encoded as the canonical one, others are rejected.
+    let nan32 = f32::from_bits(0x7fc0_0001);
+    let nan64 = f64::from_bits(0xfff8_0000_0000_0001);
+    for &float_enc in &[FloatEncoding::Normal, FloatEncoding::F16, FloatEncoding::F32, FloatEncoding::HalvePrecision] {
+        let config = Config { float_enc: float_enc, canonical: true, ..Config::default() };
+        let encoded = serialize(&(nan32, nan64), Infinite, config).unwrap();
+        assert_eq!(encoded, serialize(&(f32::NAN, f64::NAN), Infinite, config).unwrap());
+        let (a, b): (f32, f64) = deserialize(&encoded, config).unwrap();
+        assert!(a.is_nan() && b.is_nan());
+    }
+    let encoded = serialize(&nan32, Infinite, Config::default()).unwrap();
+    assert!(deserialize::<f32, _>(&encoded, Config::default()).unwrap().is_nan());
+    assert!(is_non_canonical(deserialize::<f32, _>(&encoded, canonical)));
+    let encoded = serialize(&nan64, Infinite, Config::default()).unwrap();
+    assert!(is_non_canonical(deserialize::<f64, _>(&encoded, canonical)));
+
+    // Length prefixed structs have to have exactly the fields the reader knows.
+    let canonical = Config { struct_enc: StructEncoding::LengthPrefixed, ..canonical };
+    let old_encoded = serialize(&Old { x: 1 }, Infinite, canonical).unwrap();
+    let new_encoded = serialize(&New { x: 1, y: 2 }, Infinite, canonical).unwrap();
+    assert_eq!(deserialize::<New, _>(&new_encoded, canonical).unwrap(), New { x: 1, y: 2 });
+    assert!(is_non_canonical(deserialize::<New, _>(&old_encoded, canonical)));
+    assert!(is_non_canonical(deserialize::<Old, _>(&new_encoded, canonical)));
+
+    let err = mincode::Error::from(deserialize::<u32, _>(&[0x80, 0x00], canonical).unwrap_err());
+    match *err.kind() {
+        mincode::ErrorKind::NonCanonical { .. } => {}
+        ref kind => panic!("unexpected error kind {:?}", kind),
+    }
+}
+
+#[test]
+fn test_sorted_maps() {
+    use std::collections::BTreeMap;
+
+    struct Unsized<'a>(&'a BTreeMap<String, u8>);
+
+    impl<'a> serde::Serialize for Unsized<'a> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().filter(|_| true))
+        }
+    }
+
+    let sorted = Config { sort_maps: true, ..Config::default() };
+    let canonical = Config { canonical: true, ..Config::default() };
+
+    // The same entries encode the same, however the map was built.
+    let mut a = HashMap::new();
+    let mut b = HashMap::with_capacity(1000);
+    for i in 0..100u32 {
+        a.insert(i, i.to_string());
+        b.insert(99 - i, (99 - i).to_string());
+    }
+    let encoded = serialize(&a, Infinite, sorted).unwrap();
+    assert_eq!(encoded, serialize(&b, Infinite, sorted).unwrap());
+    assert_eq!(encoded, serialize(&a, Infinite, canonical).unwrap());
+    assert_eq!(mincode::serde::serialized_size(&a, sorted), encoded.len() as u64);
+    assert_eq!(deserialize::<HashMap<u32, String>, _>(&encoded, canonical).unwrap(), a);
+
+    // Keys are sorted by their encoding: "b" is shorter than "aa", so it goes first.
+    let mut map = BTreeMap::new();
+    map.insert("aa".to_string(), 1u8);
+    map.insert("b".to_string(), 2u8);
+    let unsorted = serialize(&map, Infinite, Config::default()).unwrap();
+    assert_eq!(unsorted, vec![2, 2, b'a', b'a', 1, 1, b'b', 2]);
+    let encoded = serialize(&map, Infinite, sorted).unwrap();
+    assert_eq!(encoded, vec![2, 1, b'b', 2, 2, b'a', b'a', 1]);
+    assert_eq!(serialize(&Unsized(&map), Infinite, sorted).unwrap(), encoded);
+
+    // Only canonical mode checks the order, and rejects duplicate keys.
+    assert_eq!(deserialize::<BTreeMap<String, u8>, _>(&unsorted, Config::default()).unwrap(), map);
+    assert_eq!(deserialize::<BTreeMap<String, u8>, _>(&encoded, canonical).unwrap(), map);
+    assert!(is_non_canonical(deserialize::<BTreeMap<String, u8>, _>(&unsorted, canonical)));
+    let duplicate = [2, 1, b'a', 1, 1, b'a', 2];
+    assert_eq!(deserialize::<BTreeMap<String, u8>, _>(&duplicate, Config::default()).unwrap().len(), 1);
+    assert!(is_non_canonical(deserialize::<BTreeMap<String, u8>, _>(&duplicate, canonical)));
+
+    // Maps in keys are checked on their own.
+    let mut outer = BTreeMap::new();
+    outer.insert(vec![map.clone()], 0u8);
+    let encoded = serialize(&outer, Infinite, canonical).unwrap();
+    assert_eq!(deserialize::<BTreeMap<Vec<BTreeMap<String, u8>>, u8>, _>(&encoded, canonical).unwrap(), outer);
+}
+
+#[test]
+fn test_128_bit_integers() {
+    use mincode::rustc_serialize::{DecoderReader, EncoderWriter};
+
+    let unsigned = [0, 1, 127, 128, u64::MAX as u128, 1 << 64, 1 << 126, u128::MAX];
+    let signed = [0, -1, 63, -64, 64, i64::MIN as i128, i64::MAX as i128 + 1, i128::MIN, i128::MAX];
+    let value = (unsigned.to_vec(), signed.to_vec());
+    let encoded = serialize(&value, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(mincode::serde::serialized_size(&value, FloatEncoding::Normal), encoded.len() as u64);
+    assert_eq!(deserialize::<(Vec<u128>, Vec<i128>), _>(&encoded, FloatEncoding::Normal).unwrap(), value);
+
+    // They are LEB128 like the smaller integers, so small values stay small.
+    assert_eq!(serialize(&300u128, Infinite, FloatEncoding::Normal).unwrap(),
+               serialize(&300u64, Infinite, FloatEncoding::Normal).unwrap());
+    assert_eq!(serialize(&-300i128, Infinite, FloatEncoding::Normal).unwrap(),
+               serialize(&-300i64, Infinite, FloatEncoding::Normal).unwrap());
+    assert_eq!(serialize(&u128::MAX, Infinite, FloatEncoding::Normal).unwrap().len(), 19);
+    assert_eq!(serialize(&i128::MIN, Infinite, FloatEncoding::Normal).unwrap().len(), 19);
+
+    // The rustc_serialize backend has them on its concrete types.
+    let mut encoded = Vec::new();
+    {
+        let mut encoder = EncoderWriter::new(&mut encoded, FloatEncoding::Normal);
+        let mut checker = mincode::rustc_serialize::SizeChecker::new(u64::MAX, FloatEncoding::Normal);
+        for &v in &unsigned {
+            encoder.emit_u128(v).unwrap();
+            checker.emit_u128(v).unwrap();
+        }
+        for &v in &signed {
+            encoder.emit_i128(v).unwrap();
+            checker.emit_i128(v).unwrap();
+        }
+        assert_eq!(checker.written, encoder.bytes_written());
+    }
+    let mut reader = &encoded[..];
+    let mut decoder = DecoderReader::new(&mut reader, Infinite, FloatEncoding::Normal);
+    for &v in &unsigned {
+        assert_eq!(decoder.read_u128().unwrap(), v);
+    }
+    for &v in &signed {
+        assert_eq!(decoder.read_i128().unwrap(), v);
+    }
+
+    // Varints that exceed 128 bits overflow.
+    fn overflows<T: Debug>(res: DeserializeResult<T>) -> bool {
+        matches!(res.map_err(DeserializeError::into_inner), Err(DeserializeError::SizeLimit))
+    }
+    let mut bytes = vec![0xff; 18];
+    bytes.push(0x04);
+    assert!(overflows(deserialize::<u128, _>(&bytes, FloatEncoding::Normal)));
+    bytes[18] = 0x03;
+    assert_eq!(deserialize::<u128, _>(&bytes, FloatEncoding::Normal).unwrap(), u128::MAX);
+    bytes[18] = 0x83;
+    bytes.push(0x00);
+    assert!(overflows(deserialize::<u128, _>(&bytes, FloatEncoding::Normal)));
+    let mut bytes = vec![0x80; 18];
+    bytes.push(0x02);
+    assert!(overflows(deserialize::<i128, _>(&bytes, FloatEncoding::Normal)));
+    bytes[18] = 0x7e;
+    assert_eq!(deserialize::<i128, _>(&bytes, FloatEncoding::Normal).unwrap(), i128::MIN);
+    let mut reader = &bytes[..];
+    assert!(DecoderReader::new(&mut reader, Infinite, FloatEncoding::Normal).read_u128().is_err());
+}
+
+#[test]
+fn test_non_zero_option() {
+    use std::num::{NonZeroU8, NonZeroU32, NonZeroI64, NonZeroU128};
+    use mincode::NonZeroOption;
+
+    the_same(NonZeroOption(NonZeroU32::new(300)), FloatEncoding::Normal);
+    the_same(NonZeroOption::<NonZeroU32>(None), FloatEncoding::Normal);
+    the_same(NonZeroOption(NonZeroU8::new(255)), FloatEncoding::Normal);
+    the_same(NonZeroOption(NonZeroI64::new(i64::MIN)), FloatEncoding::Normal);
+    the_same(vec![NonZeroOption(NonZeroI64::new(-1)), NonZeroOption(None)], FloatEncoding::Normal);
+
+    // `0` is `None`, so there is no tag in front of the value.
+    let some = NonZeroOption(NonZeroU32::new(300));
+    assert_eq!(proxy_encode(&some, Infinite, FloatEncoding::Normal), vec![0xac, 0x02]);
+    assert_eq!(proxy_encode(&NonZeroOption::<NonZeroU32>(None), Infinite, FloatEncoding::Normal), vec![0]);
+    assert_eq!(proxy_encode(&Some(300u32), Infinite, FloatEncoding::Normal).len(), 3);
+    assert_eq!(proxy_encoded_size(&some, FloatEncoding::Normal), 2);
+
+    let value = (NonZeroOption(NonZeroU128::new(u128::MAX)), NonZeroOption::<NonZeroU128>(None));
+    let encoded = serialize(&value, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(encoded.len(), 19 + 1);
+    assert_eq!(deserialize::<(NonZeroOption<NonZeroU128>, NonZeroOption<NonZeroU128>), _>(&encoded, FloatEncoding::Normal).unwrap(), value);
+}
+
+#[test]
+fn test_presence_bitmap() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
+    struct Update {
+        id: u32,
+        a: Option<u32>,
+        b: Option<String>,
+        c: Option<Option<u8>>,
+        d: Option<u32>,
+        e: Option<u32>,
+        f: Option<u32>,
+        g: Option<u32>,
+        h: Option<(u8, Option<u8>)>,
+    }
+
+    let config = Config { struct_enc: StructEncoding::PresenceBitmap, ..Config::default() };
+    let check = |value: &Update| {
+        let encoded = serialize(value, Infinite, config).unwrap();
+        assert_eq!(mincode::serde::serialized_size(value, config), encoded.len() as u64);
+        assert_eq!(&deserialize::<Update, _>(&encoded, config).unwrap(), value);
+        encoded
+    };
+
+    // A sparse update is one byte of bitmap and the id, instead of a tag per field.
+    let empty = Update { id: 1, ..Update::default() };
+    assert_eq!(check(&empty), vec![0, 1]);
+    assert_eq!(serialize(&empty, Infinite, Config::default()).unwrap().len(), 1 + 8);
+    let sparse = Update { id: 1, d: Some(5), ..Update::default() };
+    assert_eq!(check(&sparse), vec![0b1000, 1, 5]);
+    // The eighth optional field goes into a second byte.
+    let last = Update { id: 1, h: Some((2, None)), ..Update::default() };
+    assert_eq!(check(&last), vec![0x80, 1, 1, 2, 0]);
+
+    // Nested `Option`s keep their tags.
+    check(&Update { c: Some(None), ..Update::default() });
+    check(&Update {
+        id: 7,
+        a: Some(1),
+        b: Some("abc".to_string()),
+        c: Some(Some(3)),
+        d: None,
+        e: Some(u32::MAX),
+        f: None,
+        g: Some(0),
+        h: Some((4, Some(5))),
+    });
+    let updates = vec![sparse, last.clone()];
+    let encoded = serialize(&updates, Infinite, config).unwrap();
+    assert_eq!(deserialize::<Vec<Update>, _>(&encoded, config).unwrap(), updates);
+
+    // Overlong bitmaps and bits past the last optional field decode, just not in canonical mode.
+    let canonical = Config { canonical: true, ..config };
+    for bytes in &[&[0x80u8, 0, 1][..], &[0x80, 0x02, 1]] {
+        assert_eq!(deserialize::<Update, _>(bytes, config).unwrap(), empty);
+        assert!(is_non_canonical(deserialize::<Update, _>(bytes, canonical)));
+    }
+    assert_eq!(deserialize::<Update, _>(&[0x80, 1, 1, 2, 0], canonical).unwrap(), last);
+    // A bitmap longer than the fields could need is invalid.
+    assert!(deserialize::<Update, _>(&[0x80, 0x80, 0, 1], config).is_err());
+}
+
 #[test]
 fn test_struct_newtype() {
     #[derive(RustcEncodable, RustcDecodable, Serialize, Deserialize, PartialEq, Debug)]
@@ -217,19 +530,119 @@ fn test_unicode() {
 
 #[test]
 fn test_fixed_size_array() {
-    the_same([24u32; 32], FloatEncoding::Normal);
-    the_same([1u64, 2, 3, 4, 5, 6, 7, 8], FloatEncoding::Normal);
-    the_same([0u8; 19], FloatEncoding::Normal);
+    // serde serializes arrays like tuples, without the length
+    // that rustc_serialize encodes in front of them.
+    fn the_same_array<V>(element: V)
+        where V: Encodable + Decodable + serde::Serialize + serde::de::DeserializeOwned + PartialEq + Debug
+    {
+        let encoded = encode(&element, Infinite, FloatEncoding::Normal).unwrap();
+        let serialized = serialize(&element, Infinite, FloatEncoding::Normal).unwrap();
+        assert_eq!(&encoded[1..], &serialized[..]);
+        assert_eq!(decode::<V>(&encoded, FloatEncoding::Normal).unwrap(), element);
+        assert_eq!(deserialize::<V, _>(&serialized, FloatEncoding::Normal).unwrap(), element);
+    }
+
+    the_same_array([24u32; 32]);
+    the_same_array([1u64, 2, 3, 4, 5, 6, 7, 8]);
+    the_same_array([0u8; 19]);
+    assert_eq!(serialize(&([1u8; 2], (2u8, 3u8)), Infinite, FloatEncoding::Normal).unwrap(), vec![1, 1, 2, 3]);
+
+    // A position in an entity costs its floats and nothing more.
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Entity {
+        id: u8,
+        position: [f32; 3],
+        velocity: [f32; 3],
+    }
+    let entity = Entity { id: 1, position: [1.0, 2.0, 3.0], velocity: [0.0; 3] };
+    let serialized = serialize(&entity, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(serialized.len(), 1 + 2 * 3 * 4);
+    assert_eq!(deserialize::<Entity, _>(&serialized, FloatEncoding::Normal).unwrap(), entity);
+
+    // Arrays behind `with` functions and wrappers of our own are arrays all the same.
+    mod bytes3 {
+        use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+        pub fn serialize<S: Serializer>(value: &[u8; 3], serializer: S) -> Result<S::Ok, S::Error> {
+            value.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 3], D::Error> {
+            <[u8; 3]>::deserialize(deserializer)
+        }
+    }
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Wrapper<T>(T);
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct W {
+        #[serde(with = "bytes3")]
+        a: [u8; 3],
+        b: Wrapper<[u8; 3]>,
+    }
+    let w = W { a: [1, 2, 3], b: Wrapper([4, 5, 6]) };
+    let serialized = serialize(&w, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(serialized, vec![1, 2, 3, 4, 5, 6]);
+    assert_eq!(deserialize::<W, _>(&serialized, FloatEncoding::Normal).unwrap(), w);
+
+    // Arrays written with their length, like rustc_serialize and older versions do, read with `len_prefixed`.
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct OldEntity {
+        id: u8,
+        #[serde(with = "mincode::serde::len_prefixed")]
+        position: [f32; 3],
+        #[serde(with = "mincode::serde::len_prefixed")]
+        velocity: [f32; 3],
+    }
+    let old = OldEntity { id: 1, position: [1.0, 2.0, 3.0], velocity: [0.0; 3] };
+    let encoded = encode(&(1u8, [1.0f32, 2.0, 3.0], [0.0f32; 3]), Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(encoded.len(), 1 + 2 * (1 + 3 * 4));
+    assert_eq!(serialize(&old, Infinite, FloatEncoding::Normal).unwrap(), encoded);
+    assert_eq!(mincode::serde::serialized_size(&old, FloatEncoding::Normal), encoded.len() as u64);
+    assert_eq!(deserialize::<OldEntity, _>(&encoded, FloatEncoding::Normal).unwrap(), old);
+    // The length has to be the one of the array.
+    for bytes in &[&[1u8, 2, 1, 2][..], &[1, 4, 1, 2, 3, 4]] {
+        #[derive(Deserialize, Debug)]
+        struct Old(u8, #[serde(with = "mincode::serde::len_prefixed")] [u8; 3]);
+        assert!(deserialize::<Old, _>(bytes, FloatEncoding::Normal).is_err());
+    }
+
+    // With `array_len_prefix`, every tuple has its length in front, however it is serialized.
+    let prefixed = Config { array_len_prefix: true, ..Config::default() };
+    let serialized = serialize(&w, Infinite, prefixed).unwrap();
+    assert_eq!(serialized, vec![3, 1, 2, 3, 3, 4, 5, 6]);
+    assert_eq!(mincode::serde::serialized_size(&w, prefixed), serialized.len() as u64);
+    assert_eq!(deserialize::<W, _>(&serialized, prefixed).unwrap(), w);
+    let value = (vec![[1u8, 2], [3, 4]], Some(Wrapper([5u16; 3])));
+    let serialized = serialize(&value, Infinite, prefixed).unwrap();
+    assert_eq!(serialized, vec![2, 2, 2, 1, 2, 2, 3, 4, 1, 3, 5, 5, 5]);
+    assert_eq!(mincode::serde::serialized_size(&value, prefixed), serialized.len() as u64);
+    assert_eq!(deserialize::<(Vec<[u8; 2]>, Option<Wrapper<[u16; 3]>>), _>(&serialized, prefixed).unwrap(), value);
+    let unprefixed = serialize(&value, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(unprefixed.len(), serialized.len() - 4);
+    let bitvec = BVec::new(BitVec::from_fn(20, |i| { i % 3 == 0 }));
+    let serialized = serialize(&bitvec, Infinite, prefixed).unwrap();
+    assert_eq!(serialized[0], 4);
+    assert_eq!(deserialize::<BVec, _>(&serialized, prefixed).unwrap(), bitvec);
+    // Old data with arrays in structs reads with it, and saves a byte per array without it.
+    assert_eq!(deserialize::<Entity, _>(&encoded, prefixed).unwrap(), entity);
+    assert_eq!(serialize(&entity, Infinite, prefixed).unwrap(), encoded);
+    assert_eq!(serialize(&entity, Infinite, FloatEncoding::Normal).unwrap().len(), encoded.len() - 2);
+    // The length has to be the one of the tuple.
+    assert!(deserialize::<[u8; 3], _>(&[2, 1, 2], prefixed).is_err());
+    assert!(deserialize::<[u8; 3], _>(&[4, 1, 2, 3, 4], prefixed).is_err());
+    assert!(deserialize::<(u8, u8), _>(&[3, 1, 2, 3], prefixed).is_err());
 }
 
 #[test]
 fn decoding_errors() {
     fn isize_invalid_encoding<T>(res: mincode::rustc_serialize::DecodingResult<T>) {
-        match res {
+        match res.map_err(DecodingError::into_inner) {
             Ok(_) => panic!("Expecting error"),
             Err(DecodingError::IoError(_)) => panic!("Expecting InvalidEncoding"),
             Err(DecodingError::SizeLimit) => panic!("Expecting InvalidEncoding"),
+            Err(DecodingError::DepthLimit) => panic!("Expecting InvalidEncoding"),
             Err(DecodingError::InvalidEncoding(_)) => {},
+            Err(DecodingError::At(..)) => unreachable!(),
         }
     }
 
@@ -248,24 +661,101 @@ fn decoding_errors() {
 #[test]
 fn deserializing_errors() {
     fn isize_invalid_deserialize<T: Debug>(res: DeserializeResult<T>) {
-        match res {
+        match res.map_err(DeserializeError::into_inner) {
             Err(DeserializeError::InvalidEncoding(_)) => {},
-            Err(DeserializeError::Serde(serde::de::value::Error::UnknownVariant(_))) => {},
-            Err(DeserializeError::Serde(serde::de::value::Error::InvalidValue(_))) => {},
-            _ => panic!("Expecting InvalidEncoding, got {:?}", res),
+            Err(DeserializeError::Serde(_)) => {},
+            res => panic!("Expecting InvalidEncoding, got {:?}", res),
         }
     }
 
-    isize_invalid_deserialize(deserialize::<bool>(&vec![0xA][..], FloatEncoding::Normal));
-    isize_invalid_deserialize(deserialize::<String>(&vec![1, 0xFF][..], FloatEncoding::Normal));
+    isize_invalid_deserialize(deserialize::<bool, _>(&vec![0xA][..], FloatEncoding::Normal));
+    isize_invalid_deserialize(deserialize::<String, _>(&vec![1, 0xFF][..], FloatEncoding::Normal));
     // Out-of-bounds variant
     #[derive(RustcEncodable, RustcDecodable, Serialize, Deserialize, Debug)]
     enum Test {
         One,
         Two,
     };
-    isize_invalid_deserialize(deserialize::<Test>(&vec![5][..], FloatEncoding::Normal));
-    isize_invalid_deserialize(deserialize::<Option<u8>>(&vec![5, 0][..], FloatEncoding::Normal));
+    isize_invalid_deserialize(deserialize::<Test, _>(&vec![5][..], FloatEncoding::Normal));
+    isize_invalid_deserialize(deserialize::<Option<u8>, _>(&vec![5, 0][..], FloatEncoding::Normal));
+}
+
+#[test]
+fn test_error_position() {
+    #[derive(RustcEncodable, RustcDecodable, Serialize, Deserialize, PartialEq, Debug)]
+    struct Entity {
+        x: bool,
+        y: u8,
+    }
+    #[derive(RustcEncodable, RustcDecodable, Serialize, Deserialize, PartialEq, Debug)]
+    struct World {
+        id: u32,
+        entities: Vec<Entity>,
+    }
+
+    // The second entity has an invalid bool, the error is reported after reading it.
+    let encoded = vec![1, 2, 1, 0, 5, 0];
+    let err = decode::<World>(&encoded, FloatEncoding::Normal).unwrap_err();
+    assert_eq!(err.position().unwrap().offset, 5);
+    assert_eq!(err.position().unwrap().path(), "World.entities[1].x");
+    match err.into_inner() {
+        DecodingError::InvalidEncoding(_) => {},
+        err => panic!("Expecting InvalidEncoding, got {:?}", err),
+    }
+
+    let err = deserialize::<World, _>(&encoded, FloatEncoding::Normal).unwrap_err();
+    assert_eq!(err.position().unwrap().offset, 5);
+    assert_eq!(err.position().unwrap().path(), "World.entities[1].x");
+    match err.into_inner() {
+        DeserializeError::InvalidEncoding(_) => {},
+        err => panic!("Expecting InvalidEncoding, got {:?}", err),
+    }
+
+    // Running out of input in a top-level value has no path.
+    let err = decode::<u32>(&[128], FloatEncoding::Normal).unwrap_err();
+    assert_eq!(err.position().unwrap().path(), "");
+
+    // Size limit errors point at the start of the value that did not fit.
+    let world = World { id: 300, entities: vec![Entity { x: true, y: 0 }, Entity { x: false, y: 200 }] };
+    let err = encode(&world, Bounded(6), FloatEncoding::Normal).unwrap_err();
+    assert_eq!(err.position().unwrap().offset, 6);
+    assert_eq!(err.position().unwrap().path(), "World.entities[1].y");
+    let err = serialize(&world, Bounded(6), FloatEncoding::Normal).unwrap_err();
+    assert_eq!(err.position().unwrap().offset, 6);
+    assert_eq!(err.position().unwrap().path(), "World.entities[1].y");
+}
+
+#[test]
+fn test_shared_error() {
+    use std::error::Error;
+    use mincode::ErrorKind;
+
+    fn decode_both(bytes: &[u8]) -> mincode::Result<(bool, bool)> {
+        let a = try!(decode(bytes, FloatEncoding::Normal));
+        let b = try!(deserialize(bytes, FloatEncoding::Normal));
+        Ok((a, b))
+    }
+
+    assert_eq!(decode_both(&[1]).unwrap(), (true, true));
+    let err = decode_both(&[5]).unwrap_err();
+    match *err.kind() {
+        ErrorKind::InvalidEncoding { .. } => {},
+        ref kind => panic!("Expecting InvalidEncoding, got {:?}", kind),
+    }
+    assert_eq!(err.position().unwrap().offset, 1);
+    assert!(err.source().is_none());
+
+    let err = mincode::Error::from(decode::<u32>(&[], FloatEncoding::Normal).unwrap_err());
+    match *err.kind() {
+        ErrorKind::Io(_) => assert!(err.source().is_some()),
+        ref kind => panic!("Expecting Io, got {:?}", kind),
+    }
+
+    let err = mincode::Error::from(serialize(&"abcde", Bounded(3), FloatEncoding::Normal).unwrap_err());
+    match err.into_kind() {
+        ErrorKind::SizeLimit => {},
+        kind => panic!("Expecting SizeLimit, got {:?}", kind),
+    }
 }
 
 #[test]
@@ -340,6 +830,76 @@ fn too_big_serialize() {
     assert!(serialize(&"abcde", Bounded(1 + 5), FloatEncoding::Normal).is_ok());
 }
 
+#[test]
+fn too_big_into_writes_nothing() {
+    let value = vec![1u32, 2, 300, 4];
+    let mut output = vec![];
+    assert!(mincode::rustc_serialize::encode_into(&value, &mut output, Bounded(5), FloatEncoding::Normal).is_err());
+    assert!(output.is_empty());
+    assert!(mincode::serde::serialize_into(&mut output, &value, Bounded(5), FloatEncoding::Normal).is_err());
+    assert!(output.is_empty());
+
+    mincode::rustc_serialize::encode_into(&value, &mut output, Bounded(6), FloatEncoding::Normal).unwrap();
+    mincode::serde::serialize_into(&mut output, &value, Bounded(6), FloatEncoding::Normal).unwrap();
+    assert_eq!(output, [4, 1, 2, 172, 2, 4, 4, 1, 2, 172, 2, 4]);
+}
+
+#[test]
+fn test_into_slice() {
+    let value = ("hello".to_string(), 300u32, -1i64);
+    let mut buffer = [0u8; 1200];
+
+    let n = mincode::rustc_serialize::encode_into_slice(&mut buffer, &value, FloatEncoding::Normal).unwrap();
+    assert_eq!(&buffer[..n], &proxy_encode(&value, Infinite, FloatEncoding::Normal)[..]);
+    let n = mincode::serde::serialize_into_slice(&mut buffer, &value, FloatEncoding::Normal).unwrap();
+    assert_eq!(&buffer[..n], &proxy_encode(&value, Infinite, FloatEncoding::Normal)[..]);
+    assert_eq!(n, 9);
+
+    let mut small = [0u8; 8];
+    match mincode::rustc_serialize::encode_into_slice(&mut small, &value, FloatEncoding::Normal) {
+        Err(mincode::rustc_serialize::EncodingError::BufferTooSmall { required: 9 }) => {},
+        res => panic!("Expecting BufferTooSmall, got {:?}", res),
+    }
+    match mincode::serde::serialize_into_slice(&mut small, &value, FloatEncoding::Normal) {
+        Err(mincode::serde::SerializeError::BufferTooSmall { required: 9 }) => {},
+        res => panic!("Expecting BufferTooSmall, got {:?}", res),
+    }
+    assert_eq!(mincode::serde::serialize_into_slice(&mut small[..0], &(), FloatEncoding::Normal).unwrap(), 0);
+}
+
+#[test]
+fn test_custom_writer_and_reader() {
+    // Implements only mincode's traits, not `std::io::{Read, Write}`.
+    struct Bytes(Vec<u8>, usize);
+    impl mincode::Writer for Bytes {
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), mincode::IoError> {
+            self.0.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+    impl mincode::Reader for Bytes {
+        fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), mincode::IoError> {
+            let end = self.1 + buf.len();
+            buf.copy_from_slice(&self.0[self.1..end]);
+            self.1 = end;
+            Ok(())
+        }
+    }
+
+    let value = (vec!["hello".to_string()], -300i32, 'λ', 1.5f64);
+    let mut bytes = Bytes(Vec::new(), 0);
+    mincode::rustc_serialize::encode_into(&value, &mut bytes, Infinite, FloatEncoding::Normal).unwrap();
+    mincode::serde::serialize_into(&mut bytes, &value, Infinite, FloatEncoding::Normal).unwrap();
+    let encoded = proxy_encode(&value, Infinite, FloatEncoding::Normal);
+    assert_eq!(bytes.0, [&encoded[..], &encoded[..]].concat());
+
+    let decoded: (Vec<String>, i32, char, f64) = decode_from(&mut bytes, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, value);
+    let decoded: (Vec<String>, i32, char, f64) = deserialize_from(&mut bytes, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, value);
+    assert_eq!(bytes.1, bytes.0.len());
+}
+
 #[test]
 fn test_proxy_encoded_size() {
     assert!(proxy_encoded_size(&0u8, FloatEncoding::Normal) == 1);
@@ -370,6 +930,254 @@ fn test_serialized_size() {
     assert!(proxy_encoded_size(&vec![0u32, 128u32, 2u32], FloatEncoding::Normal) == 1 + 1 + 2 + 1);
 }
 
+#[test]
+fn test_varint_sizes() {
+    let mut values = vec![0u64, u64::MAX];
+    for shift in 1..64 {
+        values.push((1 << shift) - 1);
+        values.push(1 << shift);
+    }
+    for &v in &values {
+        let encoded = proxy_encode(&v, Infinite, FloatEncoding::Normal);
+        assert_eq!(proxy_encoded_size(&v, FloatEncoding::Normal), encoded.len() as u64);
+
+        for &v in &[v as i64, (v as i64).wrapping_neg(), !(v as i64)] {
+            let encoded = proxy_encode(&v, Infinite, FloatEncoding::Normal);
+            assert_eq!(proxy_encoded_size(&v, FloatEncoding::Normal), encoded.len() as u64);
+        }
+    }
+}
+
+#[test]
+fn test_size_accounting() {
+    // Every byte is counted the same by the size checkers, the writers and the readers.
+    fn accounted<V>(value: V, float_enc: FloatEncoding)
+        where V: Encodable + Decodable + serde::Serialize + serde::de::DeserializeOwned + PartialEq + Debug + 'static
+    {
+        let encoded = proxy_encode(&value, Infinite, float_enc);
+        let len = encoded.len() as u64;
+        assert_eq!(proxy_encoded_size(&value, float_enc), len, "size of {:?}", value);
+
+        let mut reader = &encoded[..];
+        let mut decoder = mincode::rustc_serialize::DecoderReader::new(&mut reader, Bounded(len), float_enc);
+        assert_eq!(<V as Decodable>::decode(&mut decoder).unwrap(), value);
+        assert_eq!(decoder.bytes_read(), len, "bytes decoded of {:?}", value);
+
+        let mut reader = &encoded[..];
+        let mut deserializer = mincode::serde::Deserializer::new(&mut reader, Bounded(len), float_enc);
+        assert_eq!(<V as serde::Deserialize>::deserialize(&mut deserializer).unwrap(), value);
+        assert_eq!(deserializer.bytes_read(), len, "bytes deserialized of {:?}", value);
+
+        if len > 0 {
+            let limit = Bounded(len - 1);
+            assert!(encode(&value, limit, float_enc).is_err());
+            assert!(serialize(&value, limit, float_enc).is_err());
+            assert!(decode_from::<_, V>(&mut &encoded[..], limit, float_enc).is_err());
+            assert!(deserialize_from::<_, V, _>(&mut &encoded[..], limit, float_enc).is_err());
+        }
+    }
+
+    for &float_enc in &[FloatEncoding::Normal, FloatEncoding::F16, FloatEncoding::F32, FloatEncoding::HalvePrecision] {
+        accounted(1.5f32, float_enc);
+        accounted(-2.25f64, float_enc);
+        accounted((0.5f32, 4.0f64, vec![1.0f32; 3]), float_enc);
+    }
+    for &v in &[false, true] {
+        accounted(v, FloatEncoding::Normal);
+    }
+    for &v in &[0u8, 127, 128, 255] {
+        accounted(v, FloatEncoding::Normal);
+    }
+    for &v in &[i8::MIN, -100, -65, -64, -1, 0, 63, 64, 100, i8::MAX] {
+        accounted(v, FloatEncoding::Normal);
+    }
+    accounted(u16::MAX, FloatEncoding::Normal);
+    accounted(u32::MAX, FloatEncoding::Normal);
+    accounted(u64::MAX, FloatEncoding::Normal);
+    accounted(i16::MIN, FloatEncoding::Normal);
+    accounted(i32::MIN, FloatEncoding::Normal);
+    accounted(i64::MIN, FloatEncoding::Normal);
+    accounted(usize::MAX, FloatEncoding::Normal);
+    accounted(isize::MIN, FloatEncoding::Normal);
+    for &c in &['a', 'é', '€', '😀'] {
+        accounted(c, FloatEncoding::Normal);
+    }
+    for s in &["", "a", "héllo wörld", "😀😀"] {
+        accounted(s.to_string(), FloatEncoding::Normal);
+    }
+    accounted(Some('€'), FloatEncoding::Normal);
+    accounted(None::<i8>, FloatEncoding::Normal);
+    accounted(vec![-100i8, 100], FloatEncoding::Normal);
+    accounted((-100i8, '😀', "x".to_string(), Some(-1i64)), FloatEncoding::Normal);
+}
+
+#[test]
+fn test_decode_limits() {
+    use mincode::DecodeLimits;
+    use mincode::rustc_serialize::decode_with_limits;
+    use mincode::serde::deserialize_with_limits;
+
+    fn is_size_limit<T: Debug>(serde: DeserializeResult<T>, rustc: Result<T, DecodingError>) -> bool {
+        let serde = matches!(serde.map_err(DeserializeError::into_inner), Err(DeserializeError::SizeLimit));
+        let rustc = matches!(rustc.map_err(DecodingError::into_inner), Err(DecodingError::SizeLimit));
+        serde && rustc
+    }
+
+    let limits = DecodeLimits { max_alloc: 15, max_seq_len: 3, max_str_len: 4, ..DecodeLimits::default() };
+    let names: Vec<String> = vec!["abcd".into(), "efgh".into(), "ijkl".into()];
+    let encoded = proxy_encode(&names, Infinite, FloatEncoding::Normal);
+    let decoded: Vec<String> = deserialize_with_limits(&encoded, limits, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, names);
+    let decoded: Vec<String> = decode_with_limits(&encoded, limits, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, names);
+
+    // Too many elements, too long a string, or too much altogether.
+    for &limits in &[DecodeLimits { max_seq_len: 2, ..limits },
+                     DecodeLimits { max_str_len: 3, ..limits },
+                     DecodeLimits { max_alloc: 14, ..limits }] {
+        assert!(is_size_limit(deserialize_with_limits::<Vec<String>, _>(&encoded, limits, FloatEncoding::Normal),
+                              decode_with_limits::<Vec<String>>(&encoded, limits, FloatEncoding::Normal)));
+    }
+
+    // A five byte length claims billions of elements, the limits reject it
+    // before the `Vec` is allocated.
+    let bogus = [0xff, 0xff, 0xff, 0xff, 0x0f];
+    let limits = DecodeLimits { max_seq_len: 1 << 20, ..DecodeLimits::default() };
+    assert!(is_size_limit(deserialize_with_limits::<Vec<u64>, _>(&bogus, limits, FloatEncoding::Normal),
+                          decode_with_limits::<Vec<u64>>(&bogus, limits, FloatEncoding::Normal)));
+    let mut map_bytes = bogus.to_vec();
+    map_bytes.extend(&[1, 2]);
+    assert!(is_size_limit(deserialize_with_limits::<HashMap<u8, u8>, _>(&map_bytes, limits, FloatEncoding::Normal),
+                          decode_with_limits::<HashMap<u8, u8>>(&map_bytes, limits, FloatEncoding::Normal)));
+
+    // Without a length limit, the serde backend only preallocates for as many
+    // elements as there are bytes left in the input.
+    assert!(deserialize_with_limits::<Vec<u64>, _>(&bogus, DecodeLimits::default(), FloatEncoding::Normal).is_err());
+    assert!(deserialize_with_limits::<BVec, _>(&bogus, DecodeLimits::default(), FloatEncoding::Normal).is_err());
+    // So does plain `deserialize`, the slice is its size limit.
+    assert!(deserialize::<Vec<u64>, _>(&bogus, FloatEncoding::Normal).is_err());
+    assert!(deserialize::<BVec, _>(&bogus, FloatEncoding::Normal).is_err());
+    match deserialize::<String, _>(&[5, b'a'], FloatEncoding::Normal).map_err(DeserializeError::into_inner) {
+        Err(DeserializeError::SizeLimit) => {}
+        other => panic!("Expected SizeLimit, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_depth_limit() {
+    use mincode::DecodeLimits;
+    use mincode::rustc_serialize::decode_with_limits;
+    use mincode::serde::deserialize_with_limits;
+
+    #[derive(RustcEncodable, RustcDecodable, Serialize, Deserialize, PartialEq, Debug)]
+    enum Tree {
+        Leaf,
+        Node(Box<Tree>),
+    }
+
+    fn tree(depth: usize) -> Tree {
+        (0..depth).fold(Tree::Leaf, |tree, _| Tree::Node(Box::new(tree)))
+    }
+
+    fn is_depth_limit<T: Debug>(serde: DeserializeResult<T>, rustc: Result<T, DecodingError>) -> bool {
+        let serde = matches!(serde.map_err(DeserializeError::into_inner), Err(DeserializeError::DepthLimit));
+        let rustc = matches!(rustc.map_err(DecodingError::into_inner), Err(DecodingError::DepthLimit));
+        serde && rustc
+    }
+
+    // Every `Node` is an enum, the `Leaf` is one more.
+    the_same(tree(511), FloatEncoding::Normal);
+    let encoded = proxy_encode(&tree(512), Infinite, FloatEncoding::Normal);
+    assert!(is_depth_limit(mincode::serde::deserialize::<Tree, _>(&encoded, FloatEncoding::Normal),
+                           mincode::rustc_serialize::decode::<Tree>(&encoded, FloatEncoding::Normal)));
+
+    let limits = DecodeLimits { max_depth: 3, ..DecodeLimits::default() };
+    let nested = vec![Some(vec![1u8])];
+    let encoded = proxy_encode(&nested, Infinite, FloatEncoding::Normal);
+    let decoded: Vec<Option<Vec<u8>>> = deserialize_with_limits(&encoded, limits, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, nested);
+    let decoded: Vec<Option<Vec<u8>>> = decode_with_limits(&encoded, limits, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, nested);
+    let limits = DecodeLimits { max_depth: 2, ..limits };
+    assert!(is_depth_limit(deserialize_with_limits::<Vec<Option<Vec<u8>>>, _>(&encoded, limits, FloatEncoding::Normal),
+                           decode_with_limits::<Vec<Option<Vec<u8>>>>(&encoded, limits, FloatEncoding::Normal)));
+
+    // Hostile input: a long run of `Node` tags fails instead of overflowing the stack.
+    let mut hostile = vec![1u8; 1 << 20];
+    hostile.push(0);
+    assert!(is_depth_limit(mincode::serde::deserialize::<Tree, _>(&hostile, FloatEncoding::Normal),
+                           mincode::rustc_serialize::decode::<Tree>(&hostile, FloatEncoding::Normal)));
+    let error = mincode::Error::from(mincode::serde::deserialize::<Tree, _>(&hostile, FloatEncoding::Normal).unwrap_err());
+    match *error.kind() {
+        mincode::ErrorKind::DepthLimit => {}
+        ref kind => panic!("unexpected error kind {:?}", kind),
+    }
+
+    // An ordinary linked list takes two levels per node, the struct and its
+    // `Some`, and still decodes with the default limits.
+    #[derive(RustcEncodable, RustcDecodable, Serialize, Deserialize, PartialEq, Debug)]
+    struct Node {
+        v: u32,
+        next: Option<Box<Node>>,
+    }
+    let list = (0..200).fold(None, |next, v| Some(Box::new(Node { v: v, next: next }))).unwrap();
+    the_same(*list, FloatEncoding::Normal);
+
+    // A `Some` counts the same when its tag is in a presence bitmap.
+    let list = (0..3).fold(None, |next, v| Some(Box::new(Node { v: v, next: next }))).unwrap();
+    let bitmap = Config { struct_enc: StructEncoding::PresenceBitmap, ..Config::default() };
+    for &config in &[Config::default(), bitmap] {
+        let encoded = serialize(&list, Infinite, config).unwrap();
+        let limits = DecodeLimits { max_depth: 5, ..DecodeLimits::default() };
+        assert_eq!(deserialize_with_limits::<Node, _>(&encoded, limits, config).unwrap(), *list);
+        let limits = DecodeLimits { max_depth: 4, ..limits };
+        match deserialize_with_limits::<Node, _>(&encoded, limits, config).map_err(DeserializeError::into_inner) {
+            Err(DeserializeError::DepthLimit) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
+
+#[test]
+fn test_exact_and_with_len() {
+    use mincode::rustc_serialize::{decode_exact, decode_with_len};
+    use mincode::serde::{deserialize_exact, deserialize_with_len};
+
+    let value = (300u16, "foo".to_string());
+    let mut encoded = proxy_encode(&value, Infinite, FloatEncoding::Normal);
+    let len = encoded.len();
+    assert_eq!(len, 6);
+
+    let decoded: (u16, String) = deserialize_exact(&encoded, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, value);
+    let decoded: (u16, String) = decode_exact(&encoded, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, value);
+
+    encoded.extend(&[7, 7]);
+    let decoded: ((u16, String), usize) = deserialize_with_len(&encoded, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, (value.clone(), len));
+    let decoded: ((u16, String), usize) = decode_with_len(&encoded, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, (value.clone(), len));
+
+    // The trailing bytes are an error that points at where they start.
+    let err = deserialize_exact::<(u16, String), _>(&encoded, FloatEncoding::Normal).unwrap_err();
+    assert_eq!(err.position().unwrap().offset, len as u64);
+    match err.into_inner() {
+        DeserializeError::InvalidEncoding(_) => {}
+        err => panic!("unexpected error {:?}", err),
+    }
+    let err = decode_exact::<(u16, String)>(&encoded, FloatEncoding::Normal).unwrap_err();
+    assert_eq!(err.position().unwrap().offset, len as u64);
+    match err.into_inner() {
+        DecodingError::InvalidEncoding(_) => {}
+        err => panic!("unexpected error {:?}", err),
+    }
+
+    // Errors in the value itself are reported as before.
+    assert!(deserialize_exact::<(u16, String), _>(&encoded[..4], FloatEncoding::Normal).is_err());
+    assert!(decode_exact::<(u16, String)>(&encoded[..4], FloatEncoding::Normal).is_err());
+}
+
 #[test]
 fn encode_box() {
     the_same(Box::new(5), FloatEncoding::Normal);
@@ -491,6 +1299,36 @@ fn test_slicebox_serialize() {
     assert!(slice == &vecx[..]);
 }
 
+#[test]
+fn test_raw_value() {
+    use mincode::RawValue;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Envelope<'a> {
+        to: u32,
+        payload: RawValue<'a>,
+    }
+
+    let inner = serialize(&("opaque", vec![1.5f64, -2.0]), Infinite, FloatEncoding::Normal).unwrap();
+    let envelope = Envelope { to: 9, payload: RawValue::new(&inner) };
+    let encoded = serialize(&envelope, Infinite, FloatEncoding::Normal).unwrap();
+
+    // The payload is embedded exactly as provided.
+    let mut expected = vec![9, inner.len() as u8];
+    expected.extend(&inner);
+    assert_eq!(encoded, expected);
+
+    let relayed: Envelope<'static> = deserialize(&encoded, FloatEncoding::Normal).unwrap();
+    assert_eq!(relayed, envelope);
+    assert_eq!(serialize(&relayed, Infinite, FloatEncoding::Normal).unwrap(), encoded);
+    let payload: (String, Vec<f64>) = deserialize(&relayed.payload, FloatEncoding::Normal).unwrap();
+    assert_eq!(payload, ("opaque".to_string(), vec![1.5, -2.0]));
+
+    // Both backends agree on the encoding.
+    the_same(RawValue::boxed(inner.clone()), FloatEncoding::Normal);
+    assert_eq!(encode(&RawValue::new(&inner), Infinite, FloatEncoding::Normal).unwrap(), &encoded[1..]);
+}
+
 #[test]
 fn test_multi_strings_encode() {
     assert!(encode(&("foo", "bar", "baz"), Infinite, FloatEncoding::Normal).is_ok());
@@ -513,7 +1351,7 @@ fn test_oom_protection() {
         }
         let x = mincode::rustc_serialize::encode(&FakeVec { len: 0xffffffffffffffffu64, byte: 1 }, Bounded(11), FloatEncoding::Normal).unwrap();
         let y : Result<Vec<u8>, _> = mincode::rustc_serialize::decode_from(&mut Cursor::new(&x[..]), Bounded(11), FloatEncoding::Normal);
-        match y {
+        match y.map_err(DecodingError::into_inner) {
             Err(DecodingError::SizeLimit) => (),
             _ => panic!("error SizeLimit expected"),
         }
@@ -525,7 +1363,7 @@ fn test_oom_protection() {
         }
         let x = mincode::rustc_serialize::encode(&FakeVec { len: 0xffffffffu32, byte: 1 }, Bounded(6), FloatEncoding::Normal).unwrap();
         let y : Result<Vec<u8>, _> = mincode::rustc_serialize::decode_from(&mut Cursor::new(&x[..]), Bounded(6), FloatEncoding::Normal);
-        match y {
+        match y.map_err(DecodingError::into_inner) {
             Err(DecodingError::SizeLimit) => (),
             _ => panic!("error SizeLimit expected"),
         }
@@ -541,6 +1379,290 @@ fn path_buf() {
     assert!(path.to_str() == decoded.to_str());
 }
 
+#[test]
+fn test_deserialize_in_place() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Entity {
+        name: String,
+        x: f32,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct World {
+        id: u32,
+        entities: Vec<Entity>,
+    }
+
+    fn world(id: u32, names: &[&str]) -> World {
+        World {
+            id: id,
+            entities: names.iter().map(|name| Entity { name: name.to_string(), x: id as f32 }).collect(),
+        }
+    }
+
+    fn buffers(world: &World) -> (*const Entity, Vec<*const u8>) {
+        (world.entities.as_ptr(), world.entities.iter().map(|entity| entity.name.as_ptr()).collect())
+    }
+
+    let mut place = World { id: 0, entities: Vec::new() };
+    let bytes = serialize(&world(1, &["alpha", "beta"]), Infinite, FloatEncoding::Normal).unwrap();
+    mincode::serde::deserialize_in_place(&mut place, &bytes, FloatEncoding::Normal).unwrap();
+    assert_eq!(place, world(1, &["alpha", "beta"]));
+
+    // A message of the same size reuses the vector and the strings.
+    let before = buffers(&place);
+    let bytes = serialize(&world(2, &["gamma", "delt"]), Infinite, FloatEncoding::Normal).unwrap();
+    mincode::serde::deserialize_in_place(&mut place, &bytes, FloatEncoding::Normal).unwrap();
+    assert_eq!(place, world(2, &["gamma", "delt"]));
+    assert_eq!(buffers(&place), before);
+
+    // Fewer entities truncate the vector in place.
+    let bytes = serialize(&world(3, &["omega"]), Infinite, FloatEncoding::Normal).unwrap();
+    mincode::serde::deserialize_in_place(&mut place, &bytes, FloatEncoding::Normal).unwrap();
+    assert_eq!(place, world(3, &["omega"]));
+    assert_eq!(buffers(&place), (before.0, before.1[..1].to_vec()));
+}
+
+#[test]
+fn test_unknown_length_sequences() {
+    // Serializes the even numbers, whose count serde does not know up front.
+    struct Evens(Vec<u32>);
+
+    impl serde::Serialize for Evens {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(self.0.iter().filter(|&&x| x % 2 == 0))
+        }
+    }
+
+    struct EvenKeys(Vec<(u32, String)>);
+
+    impl serde::Serialize for EvenKeys {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().filter(|&&(k, _)| k % 2 == 0).map(|&(k, ref v)| (k, v)))
+        }
+    }
+
+    let evens = Evens((0..300).collect());
+    let expected: Vec<u32> = (0..300).filter(|x| x % 2 == 0).collect();
+    let encoded = serialize(&evens, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(encoded, serialize(&expected, Infinite, FloatEncoding::Normal).unwrap());
+    assert_eq!(mincode::serde::serialized_size(&evens, FloatEncoding::Normal), encoded.len() as u64);
+    let decoded: Vec<u32> = deserialize(&encoded, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, expected);
+
+    // Nested inside a length prefixed struct.
+    let config = Config { float_enc: FloatEncoding::Normal, struct_enc: StructEncoding::LengthPrefixed, ..Config::default() };
+    let keys = (EvenKeys(vec![(1, "a".to_string()), (2, "b".to_string()), (4, "c".to_string())]), Evens(vec![1, 2]));
+    let mut expected = HashMap::new();
+    expected.insert(2u32, "b".to_string());
+    expected.insert(4u32, "c".to_string());
+    let encoded = serialize(&keys, Infinite, config).unwrap();
+    assert_eq!(mincode::serde::serialized_size(&keys, config), encoded.len() as u64);
+    let decoded: (HashMap<u32, String>, Vec<u32>) = deserialize(&encoded, config).unwrap();
+    assert_eq!(decoded, (expected, vec![2]));
+
+    // The buffered elements count against the size limit.
+    assert!(serialize(&evens, Bounded(200), FloatEncoding::Normal).is_err());
+    assert!(serialize(&Evens(vec![2]), Bounded(2), FloatEncoding::Normal).is_ok());
+    assert!(serialize(&Evens(vec![2]), Bounded(1), FloatEncoding::Normal).is_err());
+}
+
+#[test]
+fn test_iterators() {
+    let names = ["alpha".to_string(), "beta".to_string(), "gamma".to_string()];
+    let expected = serialize(&names[1..].to_vec(), Infinite, FloatEncoding::Normal).unwrap();
+
+    let mut written = Vec::new();
+    mincode::serde::serialize_iter(&mut written, names.iter().skip(1), FloatEncoding::Normal).unwrap();
+    assert_eq!(written, expected);
+
+    let view = (7u8, mincode::SeqIter(names.iter().skip(1).map(|name| &name[..])));
+    let encoded = serialize(&view, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(&encoded[1..], &expected[..]);
+    assert_eq!(mincode::serde::serialized_size(&view, FloatEncoding::Normal), encoded.len() as u64);
+
+    // The elements are decoded one at a time.
+    let mut reader = &written[..];
+    {
+        let mut iter = mincode::serde::deserialize_iter::<_, String, _>(&mut reader, Infinite, FloatEncoding::Normal).unwrap();
+        assert_eq!(iter.size_hint(), (0, Some(2)));
+        assert_eq!(iter.next().unwrap().unwrap(), "beta");
+        assert_eq!(iter.bytes_read(), 1 + 5);
+        assert_eq!(iter.next().unwrap().unwrap(), "gamma");
+        assert!(iter.next().is_none());
+    }
+    assert!(reader.is_empty());
+
+    // A truncated element ends the iteration with its index.
+    let mut reader = &written[..written.len() - 1];
+    let results: Vec<_> = mincode::serde::deserialize_iter::<_, String, _>(&mut reader, Infinite, FloatEncoding::Normal).unwrap().collect();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[1].as_ref().unwrap_err().position().unwrap().path(), "[1]");
+
+    // A hostile length does not make collecting preallocate for it.
+    let mut reader: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+    let results: Vec<_> = mincode::serde::deserialize_iter::<_, String, _>(&mut reader, Infinite, FloatEncoding::Normal).unwrap().collect();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].is_err());
+
+    // An iterator whose length is wrong would corrupt the length prefix.
+    struct Liar(u32);
+
+    impl Iterator for Liar {
+        type Item = u32;
+        fn next(&mut self) -> Option<u32> {
+            self.0 += 1;
+            if self.0 < 4 { Some(self.0) } else { None }
+        }
+    }
+
+    impl ExactSizeIterator for Liar {
+        fn len(&self) -> usize { 2 }
+    }
+
+    assert!(mincode::serde::serialize_iter(&mut Vec::new(), Liar(0), FloatEncoding::Normal).is_err());
+}
+
+#[test]
+fn test_stream_deserializer() {
+    use mincode::StreamDeserializer;
+
+    let mut bytes = Vec::new();
+    for name in &["alpha", "beta", "gamma"] {
+        mincode::serde::serialize_into(&mut bytes, &(name.len() as u32, name), Infinite, FloatEncoding::Normal).unwrap();
+    }
+
+    let mut stream = StreamDeserializer::new(&bytes[..], Bounded(8), Infinite, FloatEncoding::Normal);
+    let values: Vec<(u32, String)> = stream.by_ref().map(Result::unwrap).collect();
+    assert_eq!(values, vec![(5, "alpha".to_string()), (4, "beta".to_string()), (5, "gamma".to_string())]);
+    assert_eq!(stream.bytes_read(), bytes.len() as u64);
+
+    // The end of the stream in the middle of a value is an error.
+    let stream = StreamDeserializer::<_, (u32, String)>::new(&bytes[..bytes.len() - 2], Infinite, Infinite, FloatEncoding::Normal);
+    let results: Vec<_> = stream.collect();
+    assert_eq!(results.len(), 3);
+    match results[2] {
+        Err(ref err) => {
+            // Offsets count from the start of the stream, the third value starts at 13.
+            assert!(err.position().unwrap().offset > 13);
+            match *err {
+                DeserializeError::At(ref inner, _) => match **inner {
+                    DeserializeError::IoError(_) => {}
+                    ref inner => panic!("unexpected error {:?}", inner),
+                },
+                ref err => panic!("unexpected error {:?}", err),
+            }
+        }
+        Ok(ref value) => panic!("decoded {:?}", value),
+    }
+
+    // Values that are too big on their own or together.
+    let results: Vec<_> = StreamDeserializer::<_, (u32, String)>::new(&bytes[..], Bounded(6), Infinite, FloatEncoding::Normal).collect();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].is_err());
+    let mut results: Vec<_> = StreamDeserializer::<_, (u32, String)>::new(&bytes[..], Bounded(8), Bounded(16), FloatEncoding::Normal).collect();
+    assert_eq!(results.len(), 3);
+    assert!(results[1].is_ok());
+    match results.pop().unwrap().unwrap_err().into_inner() {
+        DeserializeError::SizeLimit => {}
+        err => panic!("unexpected error {:?}", err),
+    }
+
+    // A stream that ends right at its total limit ends cleanly.
+    let stream = StreamDeserializer::<_, (u32, String)>::new(&bytes[..], Infinite, Bounded(bytes.len() as u64), FloatEncoding::Normal);
+    assert_eq!(stream.map(Result::unwrap).count(), 3);
+
+    let empty: &[u8] = &[];
+    assert!(StreamDeserializer::<_, u32>::new(empty, Infinite, Infinite, FloatEncoding::Normal).next().is_none());
+
+    // Values that take no bytes do not read ahead into what follows them.
+    let record = serialize(&(7u32, "after".to_string()), Infinite, FloatEncoding::Normal).unwrap();
+    let mut reader = &record[..];
+    {
+        let mut units = StreamDeserializer::<_, ()>::new(&mut reader, Infinite, Infinite, FloatEncoding::Normal);
+        assert_eq!(units.by_ref().take(3).map(Result::unwrap).count(), 3);
+        assert_eq!(units.bytes_read(), 0);
+    }
+    let mut records = StreamDeserializer::<_, (u32, String)>::new(reader, Infinite, Infinite, FloatEncoding::Normal);
+    assert_eq!(records.next().unwrap().unwrap(), (7, "after".to_string()));
+    assert!(records.next().is_none());
+}
+
+#[test]
+fn test_lazy() {
+    use mincode::Lazy;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Message {
+        id: u32,
+        payload: Lazy<Vec<String>>,
+    }
+
+    let config = Config { float_enc: FloatEncoding::Normal, struct_enc: StructEncoding::LengthPrefixed, ..Config::default() };
+    let payload = vec!["large".to_string(), "payload".to_string()];
+    let message = Message { id: 3, payload: Lazy::new(&payload, config).unwrap() };
+    let encoded = serialize(&message, Infinite, config).unwrap();
+
+    // The payload is its encoding, prefixed with the length.
+    let inner = serialize(&payload, Infinite, config).unwrap();
+    assert_eq!(message.payload.as_bytes(), &inner[..]);
+    let mut expected = vec![2 + inner.len() as u8, 3, inner.len() as u8];
+    expected.extend(&inner);
+    assert_eq!(encoded, expected);
+
+    // Decoding copies the bytes, which are encoded again as they are.
+    let decoded: Message = deserialize(&encoded, config).unwrap();
+    assert_eq!(decoded, message);
+    assert_eq!(serialize(&decoded, Infinite, config).unwrap(), encoded);
+    assert_eq!(decoded.payload.get(config).unwrap(), payload);
+
+    let forwarded = Message { id: 4, payload: Lazy::from_bytes(decoded.payload.into_bytes()) };
+    let decoded: Message = deserialize(&serialize(&forwarded, Infinite, config).unwrap(), config).unwrap();
+    assert_eq!(decoded.payload.get(config).unwrap(), payload);
+
+    // Bytes that are not a `T` only fail when the value is decoded.
+    let broken: Lazy<Vec<String>> = deserialize(&[2, 5, b'x'], config).unwrap();
+    assert!(broken.get(config).is_err());
+}
+
+#[test]
+fn test_encoder_decoder_reuse_buffers() {
+    let mut encoder = mincode::Encoder::new(Bounded(16), FloatEncoding::Normal);
+    let mut decoder = mincode::Decoder::new(Bounded(16), FloatEncoding::Normal);
+
+    let (ptr, len) = {
+        let bytes = encoder.encode(&(1u32, "hello".to_string())).unwrap();
+        assert_eq!(bytes, &serialize(&(1u32, "hello"), Infinite, FloatEncoding::Normal).unwrap()[..]);
+        (bytes.as_ptr(), bytes.len())
+    };
+
+    // Encoding replaces the previous value and keeps the buffer.
+    let bytes = encoder.encode(&(2u32, "world".to_string())).unwrap();
+    assert_eq!((bytes.as_ptr(), bytes.len()), (ptr, len));
+    let decoded: (u32, String) = decoder.decode(bytes).unwrap();
+    assert_eq!(decoded, (2, "world".to_string()));
+
+    let mut place = (0u32, String::new());
+    decoder.decode_in_place(&mut place, bytes).unwrap();
+    assert_eq!(place, (2, "world".to_string()));
+
+    // The size limit is enforced for every value.
+    assert!(encoder.encode(&vec![0u64; 16]).is_err());
+    assert!(decoder.decode::<(u32, String)>(&[1, 32, 0, 0, 0]).is_err());
+    assert_eq!(encoder.encode(&3u8).unwrap(), &[3]);
+}
+
+#[test]
+fn not_human_readable() {
+    // serde picks the compact representation of addresses for binary formats.
+    use std::net::{IpAddr, Ipv4Addr};
+    let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    let serde_encoded = serialize(&addr, Infinite, FloatEncoding::Normal).unwrap();
+    assert_eq!(serde_encoded, vec![0, 127, 0, 0, 1]);
+    let decoded: IpAddr = deserialize(&serde_encoded, FloatEncoding::Normal).unwrap();
+    assert_eq!(decoded, addr);
+}
+
 #[test]
 fn test_u8_same() {
     the_same(127u8, FloatEncoding::Normal);
@@ -617,22 +1739,22 @@ fn leb128_too_big_ints() {
         let decoded: Result<T, _> = decode_from(&mut encoded, Infinite, FloatEncoding::Normal);
         decoded.is_ok()
     }
-    fn deserialize_ok<T: serde::Deserialize>(mut serialized: &[u8]) -> bool {
+    fn deserialize_ok<T: serde::de::DeserializeOwned>(mut serialized: &[u8]) -> bool {
         let deserialized: Result<T, _> = deserialize_from(&mut serialized, Infinite, FloatEncoding::Normal);
         deserialized.is_ok()
     }
     fn proxy_decode_ok<V>(slice: &[u8]) -> bool
-        where V: Encodable + Decodable + serde::Serialize + serde::Deserialize + PartialEq + Debug + 'static
+        where V: Encodable + Decodable + serde::Serialize + serde::de::DeserializeOwned + PartialEq + Debug + 'static
     {
         decode_ok::<V>(slice) && deserialize_ok::<V>(slice)
     }
     fn proxy_encode2<V>(element: &V) -> Vec<u8>
-        where V: Encodable + Decodable + serde::Serialize + serde::Deserialize + PartialEq + Debug + 'static
+        where V: Encodable + Decodable + serde::Serialize + serde::de::DeserializeOwned + PartialEq + Debug + 'static
     {
         proxy_encode(element, Infinite, FloatEncoding::Normal)
     }
     fn proxy_decode2<V>(slice: &[u8]) -> V
-        where V: Encodable + Decodable + serde::Serialize + serde::Deserialize + PartialEq + Debug + 'static
+        where V: Encodable + Decodable + serde::Serialize + serde::de::DeserializeOwned + PartialEq + Debug + 'static
     {
         proxy_decode(slice, FloatEncoding::Normal)
     }